@@ -1,8 +1,58 @@
-use std::fmt::Display;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs, io,
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    time::Duration,
+};
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use regex::Regex;
+use ropey::{Rope, RopeSlice};
+
+use crate::{
+    encoding, filetype, grapheme,
+    hex::{self, HexView},
+    motion::{self, Motion, WordChars},
+    search,
+    textobject::TextObject,
+    undo::{self, Edit, Step, UndoTree},
+};
+
+const INDENT: &str = "    ";
+const MAX_JUMPS: usize = 100;
+
+static NEXT_BUFFER_ID: AtomicUsize = AtomicUsize::new(0);
+
+// A place the cursor jumped away from, which `Ctrl-O` goes back to.
+struct Jump {
+    buffer: usize,
+    cursor: usize,
+}
+
+// Buffers coming and going, sent to everything subscribed to `Buffers`. Buffers are named by
+// their ids, which stay the same while they are open.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuffersEvent {
+    Added(usize),
+    Removed(usize),
+    Switched { from: usize, to: usize },
+}
 
 pub struct Buffers {
     buffers: Vec<Buffer>,
     current_buffer: usize,
+    jumps: Vec<Jump>,
+    jump_index: usize,
+    // Uppercase marks in files that have been closed, which reopen the file when jumped to.
+    global_marks: HashMap<char, (String, usize)>,
+    subscribers: Vec<Sender<BuffersEvent>>,
 }
 
 impl Buffers {
@@ -10,15 +60,52 @@ impl Buffers {
         Buffers {
             buffers: vec![buffer],
             current_buffer: 0,
+            jumps: Vec::new(),
+            jump_index: 0,
+            global_marks: HashMap::new(),
+            subscribers: Vec::new(),
         }
     }
 
+    pub fn subscribe(&mut self) -> Receiver<BuffersEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn emit(&mut self, event: BuffersEvent) {
+        self.subscribers.retain(|v| v.send(event.clone()).is_ok());
+    }
+
     pub fn add_buffer(&mut self, buffer: Buffer) -> usize {
         let id = self.buffers.len();
+        self.emit(BuffersEvent::Added(buffer.id));
         self.buffers.push(buffer);
         id
     }
 
+    // Makes the buffer at index `i` the current one.
+    fn set_current(&mut self, i: usize) {
+        let from = self.get_current().id;
+        self.current_buffer = i;
+        let to = self.get_current().id;
+        if from != to {
+            self.emit(BuffersEvent::Switched { from, to });
+        }
+    }
+
+    // Puts `buffer` in place of the current one, as when its file is opened again.
+    pub fn replace_current(&mut self, buffer: Buffer) {
+        let from = self.get_current().id;
+        let to = buffer.id;
+        self.jumps.retain(|v| v.buffer != from);
+        self.jump_index = self.jump_index.min(self.jumps.len());
+        self.buffers[self.current_buffer] = buffer;
+        self.emit(BuffersEvent::Removed(from));
+        self.emit(BuffersEvent::Added(to));
+        self.emit(BuffersEvent::Switched { from, to });
+    }
+
     pub fn get_current(&self) -> &Buffer {
         unsafe {
             self.buffers.get_unchecked(self.current_buffer)
@@ -31,41 +118,149 @@ impl Buffers {
         }
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Buffer> {
+        self.buffers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Buffer> {
+        self.buffers.iter_mut()
+    }
+
     pub fn modified(&self) -> Option<&Buffer> {
         self.buffers.iter().find(|v| v.modified)
     }
 
     pub fn remove_current(&mut self) {
-        self.buffers.remove(self.current_buffer);
+        let buffer = self.buffers.remove(self.current_buffer);
+        if buffer.is_file {
+            for (&name, &cursor) in buffer.marks.iter().filter(|(v, _)| v.is_ascii_uppercase()) {
+                self.global_marks.insert(name, (buffer.name.clone(), cursor));
+            }
+        }
+        self.jumps.retain(|v| v.buffer != buffer.id);
+        self.jump_index = self.jump_index.min(self.jumps.len());
+        self.emit(BuffersEvent::Removed(buffer.id));
+
         if self.buffers.is_empty() {
-            self.buffers.push(Buffer::new("[buffer]", false, ""));
+            self.add_buffer(Buffer::new("[buffer]", false, ""));
         } else if self.current_buffer >= self.buffers.len() {
             self.current_buffer = self.buffers.len() - 1;
         }
+        let to = self.get_current().id;
+        self.emit(BuffersEvent::Switched { from: buffer.id, to });
     }
 
+    // Switching buffers counts as a jump, as do `prev` and `next`.
     pub fn next(&mut self) {
-        self.current_buffer += 1;
-        if self.current_buffer >= self.buffers.len() {
-            self.current_buffer = 0;
-        }
+        self.push_jump(self.get_current().cursor);
+        self.set_current((self.current_buffer + 1) % self.buffers.len());
     }
 
     pub fn prev(&mut self) {
-        if self.current_buffer == 0 {
-            self.current_buffer = self.buffers.len();
-        }
-        self.current_buffer -= 1;
+        self.push_jump(self.get_current().cursor);
+        self.set_current((self.current_buffer + self.buffers.len() - 1) % self.buffers.len());
     }
 
     pub fn switch(&mut self, id: usize) {
         if id < self.buffers.len() {
-            self.current_buffer = id;
+            self.push_jump(self.get_current().cursor);
+            self.set_current(id);
+        }
+    }
+
+    // Sets a mark in the current buffer. Uppercase marks are global, so setting one takes it away
+    // from whichever buffer had it before.
+    pub fn set_mark(&mut self, name: char) {
+        if name.is_ascii_uppercase() {
+            self.global_marks.remove(&name);
+            for buffer in self.buffers.iter_mut() {
+                buffer.marks.remove(&name);
+            }
+        }
+        let buffer = self.get_current_mut();
+        buffer.marks.insert(name, buffer.cursor);
+    }
+
+    // Switches to the buffer an uppercase mark is in, opening its file again if it was closed,
+    // and records the jump. Returns false if the mark is not set.
+    pub fn goto_global_mark(&mut self, name: char) -> io::Result<bool> {
+        let cursor = self.get_current().cursor;
+        if let Some(i) = self.buffers.iter().position(|v| v.marks.contains_key(&name)) {
+            self.push_jump(cursor);
+            self.set_current(i);
+            return Ok(true);
+        }
+
+        let (path, mark) = match self.global_marks.get(&name) {
+            Some(v) => v.clone(),
+            None => return Ok(false),
+        };
+        let i = match self.buffers.iter().position(|v| v.is_file && v.name == path) {
+            Some(i) => i,
+            None => self.add_buffer(Buffer::open(&path)?),
+        };
+        self.push_jump(cursor);
+        self.global_marks.remove(&name);
+        self.set_current(i);
+        let buffer = self.get_current_mut();
+        buffer.marks.insert(name, mark.min(buffer.text.len_chars()));
+        Ok(true)
+    }
+
+    // Adds the cursor's position in the current buffer to the jump list, before a jump away from
+    // it. An older entry for the same line is dropped, and the `'` mark is set to go back to it.
+    pub fn push_jump(&mut self, cursor: usize) {
+        self.get_current_mut().marks.insert('\'', cursor);
+
+        let buffer = &self.buffers[self.current_buffer];
+        let line = |cursor: usize| buffer.text.char_to_line(cursor.min(buffer.text.len_chars()));
+        let id = buffer.id;
+        self.jumps
+            .retain(|v| v.buffer != id || line(v.cursor) != line(cursor));
+        self.jumps.push(Jump { buffer: id, cursor });
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.jump_index = self.jumps.len();
+    }
+
+    pub fn jump_back(&mut self, count: usize) -> bool {
+        // Going back from the newest entry remembers where the cursor is, for `Ctrl-I` to return.
+        if self.jump_index == self.jumps.len() {
+            let cursor = self.get_current().cursor;
+            self.push_jump(cursor);
+            self.jump_index -= 1;
+        }
+        if count > self.jump_index {
+            return false;
+        }
+        self.goto_jump(self.jump_index - count)
+    }
+
+    pub fn jump_forward(&mut self, count: usize) -> bool {
+        if self.jump_index + count >= self.jumps.len() {
+            return false;
+        }
+        self.goto_jump(self.jump_index + count)
+    }
+
+    fn goto_jump(&mut self, index: usize) -> bool {
+        let jump = &self.jumps[index];
+        match self.buffers.iter().position(|v| v.id == jump.buffer) {
+            Some(i) => {
+                let cursor = jump.cursor;
+                self.set_current(i);
+                self.get_current_mut().set_cursor_index(cursor);
+                self.jump_index = index;
+                true
+            }
+            None => false,
         }
     }
 }
 
 pub struct Buffer {
+    id: usize,
     pub name: String,
     pub is_file: bool,
     pub modified: bool,
@@ -74,30 +269,222 @@ pub struct Buffer {
     pub hscroll: usize,
     update_hscroll: bool,
 
-    pre: Vec<BufferLine>,
-    post: Vec<BufferLine>,
+    text: Rope,
+    cursor: usize,
+    history: UndoTree,
+    disk_hash: Option<u64>,
+    line_ending: LineEnding,
+    // Set when the file had more than one kind of line ending, which writing it would lose.
+    mixed_endings: bool,
+    final_newline: bool,
+    encoding: &'static Encoding,
+    bom: bool,
+    disk_format: FileFormat,
+    hex: Option<HexView>,
+    selection: Option<Selection>,
+    last_selection: Option<Selection>,
+    filetype: String,
+    word_chars: WordChars,
+    view_width: usize,
+    view_height: usize,
+    // Long lines are wrapped onto as many screen rows as they need instead of scrolling sideways.
+    wrap: bool,
+    // Marks are kept as character positions that move with the text around them as it changes.
+    marks: HashMap<char, usize>,
+    // The column that moving up and down aims for, kept across lines that are too short for it.
+    // None means the cursor's own column.
+    desired_col: Option<DesiredColumn>,
+    // Goes up by one with every change to the text, undos and redos included.
+    version: u64,
+    subscribers: Vec<Sender<BufferEvent>>,
+    extmarks: Vec<Extmark>,
+    next_extmark: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SelectionKind {
+    Char,
+    Line,
+    Block,
+}
+
+// A region of the buffer between two positions, both of which are included in it.
+#[derive(Clone, Copy)]
+pub struct Selection {
+    pub kind: SelectionKind,
+    pub anchor: usize,
+    pub cursor: usize,
+}
+
+// A place in the text as a line and a char offset into it. A column past the end of its line is
+// taken to be the end of that line, and a line past the last one is the end of the text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+// A change to a buffer's text, sent to everything subscribed to it along with the version it
+// brings the buffer to. An insert's range is where the text ends up and a delete's is where the
+// text was before it went.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BufferEvent {
+    Inserted { version: u64, range: Range<Position>, text: String },
+    Deleted { version: u64, range: Range<Position>, text: String },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Lower,
+    Upper,
+    Toggle,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    None,
+    Selection,
+    Search,
+    CurrentMatch,
+    Extmark(ExtmarkStyle),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtmarkStyle {
+    Underline,
+    Error,
+    Warning,
+    Info,
+}
+
+// A range of text that something outside the buffer has attached a style or data to, and that
+// moves with the text around it as it is edited. Text inserted at the start of the range goes
+// before it and text inserted at the end goes after it, so the range only grows from inside. An
+// empty range is an anchor, which moves like a mark.
+#[derive(Clone, Debug)]
+pub struct Extmark {
+    pub id: usize,
+    pub namespace: String,
+    pub range: Range<usize>,
+    pub style: Option<ExtmarkStyle>,
+    pub data: String,
+}
+
+// One screen row of a line: the chars it shows and the indent it is shown with. Only rows that
+// continue a wrapped line are indented.
+#[derive(Clone, Copy)]
+struct Row {
+    start: usize,
+    end: usize,
+    indent: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DesiredColumn {
+    Column(usize),
+    // After `$`, moving up and down stays at the end of each line.
+    EndOfLine,
+}
+
+// Everything besides the text itself that decides what the buffer looks like on disk.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FileFormat {
+    line_ending: LineEnding,
+    mixed_endings: bool,
+    final_newline: bool,
+    encoding: &'static Encoding,
+    bom: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(LineEnding::Lf),
+            "dos" => Some(LineEnding::CrLf),
+            "mac" => Some(LineEnding::Cr),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "unix",
+            LineEnding::CrLf => "dos",
+            LineEnding::Cr => "mac",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    // Picks whichever line ending the text uses most, preferring LF when there are none.
+    pub fn detect(text: &str) -> Self {
+        let (lf, crlf, cr) = LineEnding::count(text);
+        if crlf > lf && crlf >= cr {
+            LineEnding::CrLf
+        } else if cr > lf && cr > crlf {
+            LineEnding::Cr
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    // Whether the text uses more than one kind of line ending.
+    pub fn is_mixed(text: &str) -> bool {
+        let (lf, crlf, cr) = LineEnding::count(text);
+        [lf, crlf, cr].iter().filter(|&&v| v > 0).count() > 1
+    }
+
+    // How many LF, CRLF and CR line endings the text has.
+    fn count(text: &str) -> (usize, usize, usize) {
+        let bytes = text.as_bytes();
+        let mut lf = 0;
+        let mut crlf = 0;
+        let mut cr = 0;
+        for (i, &c) in bytes.iter().enumerate() {
+            match c {
+                b'\n' if i > 0 && bytes[i - 1] == b'\r' => crlf += 1,
+                b'\n' => lf += 1,
+                b'\r' if bytes.get(i + 1) != Some(&b'\n') => cr += 1,
+                _ => (),
+            }
+        }
+        (lf, crlf, cr)
+    }
 }
 
+// Lines are always separated by `\n` inside the buffer. The line ending and the final newline are
+// only added back when the buffer is written out.
 impl Display for Buffer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut first = true;
-        for line in self.pre.iter() {
-            if first {
-                first = false;
+        let ending = self.line_ending.as_str();
+        for chunk in self.text.chunks() {
+            if self.line_ending == LineEnding::Lf {
+                write!(f, "{}", chunk)?;
             } else {
-                writeln!(f)?;
+                for (i, part) in chunk.split('\n').enumerate() {
+                    if i > 0 {
+                        write!(f, "{}", ending)?;
+                    }
+                    write!(f, "{}", part)?;
+                }
             }
-            write!(f, "{}", line.pre)?;
-            write!(f, "{}", line.post)?;
         }
-        for line in self.post.iter() {
-            if first {
-                first = false;
-            } else {
-                writeln!(f)?;
-            }
-            write!(f, "{}", line.pre)?;
-            write!(f, "{}", line.post)?;
+
+        if self.final_newline {
+            write!(f, "{}", ending)?;
         }
 
         Ok(())
@@ -106,186 +493,1956 @@ impl Display for Buffer {
 
 impl Buffer {
     pub fn new(name: &str, is_file: bool, contents: &str) -> Self {
-        let mut post: Vec<_> = contents.split('\n').map(|v| BufferLine {
-            pre: String::new(),
-            post: v.to_owned(),
-        }).collect();
-
-        let pre = if post.is_empty() {
-            vec![BufferLine { pre: String::new(), post: String::new(), }]
+        let line_ending = LineEnding::detect(contents);
+        let mixed_endings = LineEnding::is_mixed(contents);
+        let stripped = ["\r\n", "\n", "\r"].iter().find_map(|v| contents.strip_suffix(v));
+        let final_newline = stripped.is_some();
+        let contents = stripped.unwrap_or(contents);
+        let text = if contents.contains('\r') {
+            Rope::from_str(&contents.replace("\r\n", "\n").replace('\r', "\n"))
         } else {
-            vec![post.remove(0)]
+            Rope::from_str(contents)
         };
 
+        let filetype = filetype::detect(name);
         Buffer {
+            id: NEXT_BUFFER_ID.fetch_add(1, Ordering::Relaxed),
             name: name.to_owned(),
             is_file,
             modified: false,
-            pre,
-            post,
+            text,
+            cursor: 0,
             vscroll: 0,
             update_vscroll: false,
             hscroll: 0,
             update_hscroll: false,
+            history: UndoTree::new(),
+            disk_hash: None,
+            line_ending,
+            mixed_endings,
+            final_newline,
+            encoding: UTF_8,
+            bom: false,
+            disk_format: FileFormat {
+                line_ending,
+                mixed_endings,
+                final_newline,
+                encoding: UTF_8,
+                bom: false,
+            },
+            hex: None,
+            selection: None,
+            last_selection: None,
+            filetype: filetype.to_owned(),
+            word_chars: WordChars::for_filetype(filetype),
+            view_width: 0,
+            view_height: 0,
+            wrap: false,
+            marks: HashMap::new(),
+            desired_col: None,
+            version: 0,
+            subscribers: Vec::new(),
+            extmarks: Vec::new(),
+            next_extmark: 0,
+        }
+    }
+
+    pub fn new_hex(name: &str, bytes: Vec<u8>) -> Self {
+        let mut buffer = Buffer::new(name, true, "");
+        buffer.hex = Some(HexView::new(bytes));
+        buffer
+    }
+
+    // Opens a file as text if it looks like text in some encoding, and as bytes otherwise.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let bytes = fs::read(name)?;
+        let (encoding, bom) = encoding::detect(&bytes);
+        if !bom && encoding != UTF_16LE && encoding != UTF_16BE && hex::is_binary(&bytes) {
+            return Ok(Buffer::new_hex(name, bytes));
+        }
+
+        match Buffer::from_bytes(name, &bytes, encoding) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(Buffer::new_hex(name, bytes)),
+            v => v,
+        }
+    }
+
+    pub fn open_with_encoding(name: &str, encoding: &'static Encoding) -> io::Result<Self> {
+        let bytes = fs::read(name)?;
+        Buffer::from_bytes(name, &bytes, encoding)
+    }
+
+    fn from_bytes(name: &str, bytes: &[u8], encoding: &'static Encoding) -> io::Result<Self> {
+        let (contents, bom) = encoding::decode(bytes, encoding)?;
+        let mut buffer = Buffer::new(name, true, &contents);
+        buffer.encoding = encoding;
+        buffer.bom = bom;
+        buffer.disk_format = buffer.file_format();
+
+        // The history is tied to the decoded text rather than the raw bytes, since that is what
+        // the positions in it refer to.
+        let content_hash = undo::hash(contents.as_bytes());
+        if let Some(history) = undo::cache_dir().and_then(|v| UndoTree::load(Path::new(name), &v, content_hash)) {
+            buffer.history = history;
+        }
+        buffer.disk_hash = Some(content_hash);
+        Ok(buffer)
+    }
+
+    pub fn write(&mut self) -> io::Result<()> {
+        if let Some(hex) = self.hex.as_mut() {
+            fs::write(&self.name, hex.bytes())?;
+            hex.dirty = false;
+            self.mark_saved();
+            return Ok(());
+        }
+
+        if self.mixed_endings {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file has mixed line endings, pick one with `:set fileformat` to write it",
+            ));
+        }
+
+        let contents = self.to_string();
+        fs::write(&self.name, encoding::encode(&contents, self.encoding, self.bom)?)?;
+        self.mark_saved();
+        self.disk_hash = Some(undo::hash(contents.as_bytes()));
+        let _ = self.save_undo_history();
+        Ok(())
+    }
+
+    pub fn save_undo_history(&mut self) -> io::Result<()> {
+        self.commit();
+        match (self.disk_hash, undo::cache_dir()) {
+            (Some(content_hash), Some(cache_dir)) if self.is_file => {
+                self.history.save(Path::new(&self.name), &cache_dir, content_hash)
+            }
+
+            _ => Ok(()),
         }
     }
 
-    pub fn window(&self, width: usize, height: usize) -> BufferWindow {
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn mixed_endings(&self) -> bool {
+        self.mixed_endings
+    }
+
+    // Choosing a line ending for a file that mixes them makes every line use it.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+        self.mixed_endings = false;
+        self.update_modified();
+    }
+
+    pub fn final_newline(&self) -> bool {
+        self.final_newline
+    }
+
+    pub fn set_final_newline(&mut self, final_newline: bool) {
+        self.final_newline = final_newline;
+        self.update_modified();
+    }
+
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: &'static Encoding) {
+        self.encoding = encoding;
+        self.update_modified();
+    }
+
+    pub fn bom(&self) -> bool {
+        self.bom
+    }
+
+    pub fn set_bom(&mut self, bom: bool) {
+        self.bom = bom;
+        self.update_modified();
+    }
+
+    pub fn filetype(&self) -> &str {
+        &self.filetype
+    }
+
+    // Changing the filetype resets the word characters to its defaults.
+    pub fn set_filetype(&mut self, filetype: &str) {
+        self.filetype = filetype.to_owned();
+        self.word_chars = WordChars::for_filetype(filetype);
+    }
+
+    pub fn word_chars(&self) -> &WordChars {
+        &self.word_chars
+    }
+
+    pub fn set_word_chars(&mut self, word_chars: WordChars) {
+        self.word_chars = word_chars;
+    }
+
+    pub fn wrap(&self) -> bool {
+        self.wrap
+    }
+
+    pub fn set_wrap(&mut self, wrap: bool) {
+        self.wrap = wrap;
+        self.hscroll = 0;
+        self.update_vscroll = true;
+        self.update_hscroll = true;
+    }
+
+    pub fn window(&self, width: usize, height: usize) -> BufferWindow<'_> {
+        let lines = self.vscroll..self.vscroll + height;
+        let highlights = match self.selection() {
+            Some(selection) => self
+                .selection_ranges(&selection, lines)
+                .into_iter()
+                .map(|v| (v, Highlight::Selection))
+                .collect(),
+            None => Vec::new(),
+        };
+
         BufferWindow {
             buffer: self,
             i: 0,
             width,
             height,
+            highlights,
+            line: self.vscroll,
+            rows: VecDeque::new(),
         }
     }
 
-    pub fn move_left(&mut self) {
-        if let Some(c) = self.pre.last_mut().unwrap().pre.pop() {
-            self.pre.last_mut().unwrap().post.insert(0, c);
-        } else if self.pre.len() > 1 {
-            self.post.insert(0, self.pre.pop().unwrap());
-            self.update_vscroll = true;
-        }
+    // The line each screen row starts, for numbering them, with None for the rows that continue
+    // a wrapped line.
+    pub fn screen_lines(&self, width: usize, height: usize) -> Vec<Option<usize>> {
+        (self.vscroll..self.line_count())
+            .flat_map(|line| {
+                let rows = self.rows(line, width).len();
+                (0..rows).map(move |r| (r == 0).then_some(line))
+            })
+            .take(height)
+            .collect()
+    }
 
-        self.update_hscroll = true;
+    pub fn hex(&self) -> Option<&HexView> {
+        self.hex.as_ref()
     }
 
-    pub fn move_down(&mut self) {
-        if !self.post.is_empty() {
-            self.pre.push(self.post.remove(0));
-            self.update_vscroll = true;
-        }
+    pub fn text(&self) -> &Rope {
+        &self.text
     }
 
-    pub fn move_up(&mut self) {
-        if self.pre.len() > 1 {
-            self.post.insert(0, self.pre.pop().unwrap());
-            self.update_vscroll = true;
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<BufferEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn emit(&mut self, event: BufferEvent) {
+        self.subscribers.retain(|v| v.send(event.clone()).is_ok());
+    }
+
+    // The cursor as a line and a char offset into it.
+    pub fn cursor(&self) -> (usize, usize) {
+        let position = self.char_to_position(self.cursor);
+        (position.line, position.col)
+    }
+
+    // Moves the cursor to a line and a char offset into it, stopping at the end of the line or
+    // of the text if either is too far.
+    pub fn set_cursor(&mut self, line: usize, col: usize) {
+        self.set_cursor_index(self.position_to_char(Position { line, col }));
+    }
+
+    // The cursor as a char index into the text.
+    pub fn cursor_index(&self) -> usize {
+        self.cursor
+    }
+
+    // Replaces a range of chars with `text` as a recorded edit.
+    pub fn replace(&mut self, range: Range<usize>, text: &str) {
+        let at = range.start;
+        self.delete_text(range);
+        self.insert_text(at, text);
+    }
+
+    pub fn position_to_char(&self, position: Position) -> usize {
+        if position.line >= self.line_count() {
+            return self.text.len_chars();
         }
+        self.text.line_to_char(position.line) + position.col.min(self.line_len(position.line))
     }
 
-    pub fn move_right(&mut self) {
-        if !self.pre.last().unwrap().post.is_empty() {
-            let c = self.pre.last_mut().unwrap().post.remove(0);
-            self.pre.last_mut().unwrap().pre.push(c);
-        } else if !self.post.is_empty() {
-            self.pre.push(self.post.remove(0));
-            self.update_vscroll = true;
+    pub fn char_to_position(&self, i: usize) -> Position {
+        let i = i.min(self.text.len_chars());
+        let line = self.text.char_to_line(i);
+        Position {
+            line,
+            col: i - self.text.line_to_char(line),
         }
+    }
 
-        self.update_hscroll = true;
+    // Inserts `text` at `at` as a single recorded edit. The cursor stays with the text it is on.
+    pub fn insert_str_at(&mut self, at: Position, text: &str) {
+        let at = self.position_to_char(at);
+        let cursor = self.cursor;
+        self.insert_text(at, text);
+        if cursor >= at {
+            self.set_cursor_index(cursor + text.chars().count());
+        }
     }
 
-    pub fn backspace(&mut self) {
-        if self.pre.last_mut().unwrap().pre.pop().is_none()
-            && self.pre.len() > 1
-        {
-            let last = self.pre.pop().unwrap();
-            self.pre.last_mut().unwrap().post.push_str(&last.post);
-            self.update_vscroll = true;
+    // Deletes the text between two positions and returns it. A cursor inside the range ends up
+    // at its start.
+    pub fn delete_range(&mut self, range: Range<Position>) -> String {
+        let range = self.char_range(range);
+        let text = self.text.slice(range.clone()).to_string();
+        let cursor = self.cursor;
+        self.delete_text(range.clone());
+        if cursor >= range.end {
+            self.set_cursor_index(cursor - range.len());
+        } else if cursor > range.start {
+            self.set_cursor_index(range.start);
         }
-        self.modified = true;
-        self.update_hscroll = true;
+        text
     }
 
-    pub fn enter(&mut self) {
-        let mut post = String::new();
-        std::mem::swap(&mut post, &mut self.pre.last_mut().unwrap().post);
-        self.pre.push(BufferLine { pre: String::new(), post, });
-        self.update_vscroll = true;
-        self.modified = true;
+    pub fn replace_range(&mut self, range: Range<Position>, text: &str) -> String {
+        let at = range.start;
+        let old = self.delete_range(range);
+        self.insert_str_at(at, text);
+        old
     }
 
-    pub fn char(&mut self, c: char) {
-        self.pre.last_mut().unwrap().pre.push(c);
-        self.update_vscroll = true;
-        self.update_hscroll = true;
-        self.modified = true;
+    pub fn text_in_range(&self, range: Range<Position>) -> String {
+        let range = self.char_range(range);
+        self.text.slice(range).to_string()
     }
 
-    pub fn update_scrolls(&mut self, width: isize, height: isize) {
-        if self.update_vscroll {
-            self.update_vscroll = false;
+    fn char_range(&self, range: Range<Position>) -> Range<usize> {
+        let (start, end) = (range.start.min(range.end), range.start.max(range.end));
+        self.position_to_char(start)..self.position_to_char(end)
+    }
 
-            if self.pre.len() as isize - (self.vscroll as isize) > height {
-                self.vscroll = self.pre.len() - height as usize;
-            } else if self.pre.len() as isize - (self.vscroll as isize) <= 0 {
-                self.vscroll = self.pre.len() - 1;
-            }
-        }
+    // Line `n` without its line break.
+    pub fn line(&self, n: usize) -> Option<RopeSlice<'_>> {
+        (n < self.line_count()).then(|| {
+            let start = self.text.line_to_char(n);
+            self.text.slice(start..start + self.line_len(n))
+        })
+    }
 
-        if self.update_hscroll {
-            self.update_hscroll = false;
+    pub fn lines(&self) -> impl Iterator<Item = RopeSlice<'_>> {
+        (0..self.line_count()).filter_map(move |n| self.line(n))
+    }
 
-            let v = self.pre.last().unwrap().pre.len();
-            if v as isize - (self.hscroll as isize) > width - 1 {
-                self.hscroll = v - width as usize + 1;
-            } else if v as isize - (self.hscroll as isize) <= 0 {
-                self.hscroll = v;
-            }
+    // Moves the cursor to the next match of `regex` after `from`, or the previous one before it.
+    // Returns whether the search wrapped around, or None if nothing matched.
+    pub fn search(&mut self, regex: &Regex, from: usize, forward: bool) -> Option<bool> {
+        if self.hex.is_some() {
+            return None;
         }
+
+        let (range, wrapped) = search::find(&self.text, regex, from, forward)?;
+        self.set_cursor_index(range.start);
+        Some(wrapped)
     }
 
-    pub fn cursor_pos(&self, x: usize, y: usize) -> (usize, usize) {
-        (x + self.pre.last().unwrap().pre.len() - self.hscroll, y as usize + self.pre.len() - self.vscroll - 1)
+    // Moves the cursor to a byte offset in the file.
+    pub fn goto_byte(&mut self, offset: usize) -> bool {
+        if let Some(hex) = self.hex.as_mut() {
+            return hex.goto(offset);
+        }
+
+        if offset > self.text.len_bytes() {
+            return false;
+        }
+        self.set_cursor_index(self.text.byte_to_char(offset));
+        true
     }
 
-    pub fn line_count(&self) -> usize {
-        self.pre.len() + self.post.len()
+    pub fn move_left(&mut self) {
+        if let Some(hex) = self.hex.as_mut() {
+            hex.move_left();
+        } else if self.cursor > 0 {
+            self.set_cursor_index(grapheme::prev_boundary(&self.text.slice(..), self.cursor));
+        }
     }
-}
 
-struct BufferLine {
-    pre: String,
-    post: String,
-}
+    pub fn move_down(&mut self) {
+        if let Some(hex) = self.hex.as_mut() {
+            hex.move_down();
+            return;
+        }
 
+        let line = self.text.char_to_line(self.cursor);
+        if line + 1 < self.line_count() {
+            self.move_vertically(line + 1);
+        }
+    }
 
-pub struct BufferWindow<'a> {
-    buffer: &'a Buffer,
-    i: usize,
-    width: usize,
-    height: usize,
-}
+    pub fn move_up(&mut self) {
+        if let Some(hex) = self.hex.as_mut() {
+            hex.move_up();
+            return;
+        }
 
-impl<'a> Iterator for BufferWindow<'a> {
-    type Item = Vec<&'a str>;
+        let line = self.text.char_to_line(self.cursor);
+        if line > 0 {
+            self.move_vertically(line - 1);
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.i < self.height {
-            let i = self.i + self.buffer.vscroll;
-            self.i += 1;
-            let line = if let Some(v) = self.buffer.pre.get(i) {
-                v
-            } else if let Some(v) = self.buffer.post.get(i - self.buffer.pre.len()) {
-                v
-            } else {
-                return None;
-            };
-            let v = vec![line.pre.as_str(), line.post.as_str()];
-
-            let mut j = 0;
-            Some(v.into_iter().filter_map(|v| {
-                let bound_left = j <= self.buffer.hscroll && self.buffer.hscroll < j + v.len();
-                let bound_right = j <= self.buffer.hscroll + self.width && self.buffer.hscroll + self.width < j + v.len();
-                let u = if !bound_left && !bound_right {
-                    if self.buffer.hscroll < j && j + v.len() < self.buffer.hscroll + self.width {
-                        Some(v)
-                    } else {
-                        None
-                    }
-                } else if !bound_left && bound_right {
-                    Some(&v[..self.buffer.hscroll + self.width - j])
-                } else if bound_left && !bound_right {
-                    Some(&v[self.buffer.hscroll - j..])
-                } else {
-                    Some(&v[self.buffer.hscroll - j..self.buffer.hscroll + self.width - j])
-                };
-                j += v.len();
-                u
-            }).collect())
-        } else {
-            None
+    pub fn move_right(&mut self) {
+        if let Some(hex) = self.hex.as_mut() {
+            hex.move_right();
+        } else if self.cursor < self.text.len_chars() {
+            self.set_cursor_index(grapheme::next_boundary(&self.text.slice(..), self.cursor));
+        }
+    }
+
+    // Where a motion repeated `count` times takes the cursor, or None if it cannot move at all.
+    pub fn motion_target(&self, motion: Motion, count: Option<usize>) -> Option<usize> {
+        if self.hex.is_some() {
+            return None;
         }
+
+        let slice = self.text.slice(..);
+        let words = &self.word_chars;
+        let n = count.unwrap_or(1).max(1);
+        let line = self.text.char_to_line(self.cursor);
+        let last_line = self.line_count() - 1;
+        let line_start = self.text.line_to_char(line);
+        let line_end = line_start + self.line_len(line);
+        let repeat = |f: &dyn Fn(usize) -> usize| (0..n).fold(self.cursor, |i, _| f(i));
+
+        let target = match motion {
+            Motion::Left => {
+                if self.cursor == line_start {
+                    return None;
+                }
+                repeat(&|i| grapheme::prev_boundary(&slice, i).max(line_start))
+            }
+
+            Motion::Right => {
+                if self.cursor >= line_end {
+                    return None;
+                }
+                repeat(&|i| grapheme::next_boundary(&slice, i).min(line_end))
+            }
+
+            Motion::Up => {
+                if line == 0 {
+                    return None;
+                }
+                self.desired_target(line.saturating_sub(n))
+            }
+
+            Motion::Down => {
+                if line == last_line {
+                    return None;
+                }
+                self.desired_target((line + n).min(last_line))
+            }
+
+            Motion::RowUp | Motion::RowDown if !self.wrap => {
+                let motion = if motion == Motion::RowUp { Motion::Up } else { Motion::Down };
+                return self.motion_target(motion, count);
+            }
+
+            Motion::RowUp | Motion::RowDown => self.row_target(motion == Motion::RowDown, n)?,
+
+            Motion::WordStart { big } => repeat(&|i| motion::word_start(&slice, i, words, big)),
+            Motion::WordEnd { big } => repeat(&|i| motion::word_end(&slice, i, words, big)),
+            Motion::WordBack { big } => repeat(&|i| motion::word_back(&slice, i, words, big)),
+            Motion::LineStart => line_start,
+            Motion::FirstNonBlank => motion::first_non_blank(&slice, line),
+
+            Motion::LineEnd => {
+                let line = (line + n - 1).min(last_line);
+                let start = self.text.line_to_char(line);
+                let end = start + self.line_len(line);
+                if end > start {
+                    grapheme::prev_boundary(&slice, end)
+                } else {
+                    start
+                }
+            }
+
+            Motion::FirstLine | Motion::LastLine => {
+                let line = match (count, motion) {
+                    (Some(v), _) => v.clamp(1, last_line + 1) - 1,
+                    (None, Motion::FirstLine) => 0,
+                    (None, _) => last_line,
+                };
+                motion::first_non_blank(&slice, line)
+            }
+
+            Motion::Find { c, forward, till } => {
+                motion::find_in_line(&slice, self.cursor, (c, forward, till), n, false)?
+            }
+
+            Motion::RepeatFind { c, forward, till } => {
+                motion::find_in_line(&slice, self.cursor, (c, forward, till), n, true)?
+            }
+
+            Motion::Mark { name, line: true } => {
+                motion::first_non_blank(&slice, self.text.char_to_line(self.mark(name)?))
+            }
+
+            Motion::Mark { name, line: false } => self.mark(name)?,
+
+            Motion::ParagraphForward => {
+                repeat(&|i| motion::paragraph_forward(&slice, self.text.char_to_line(i)))
+            }
+
+            Motion::ParagraphBackward => {
+                repeat(&|i| motion::paragraph_backward(&slice, self.text.char_to_line(i)))
+            }
+
+            Motion::ScreenTop | Motion::ScreenMiddle | Motion::ScreenBottom => {
+                let visible = self.visible_lines().max(1);
+                let offset = match motion {
+                    Motion::ScreenTop => (n - 1).min(visible - 1),
+                    Motion::ScreenMiddle => (visible - 1) / 2,
+                    _ => visible.saturating_sub(n),
+                };
+                motion::first_non_blank(&slice, (self.vscroll + offset).min(last_line))
+            }
+        };
+        Some(target)
+    }
+
+    pub fn mark(&self, name: char) -> Option<usize> {
+        self.marks.get(&name).map(|&v| v.min(self.text.len_chars()))
+    }
+
+    // Adds an extmark over a range of chars and returns its id.
+    pub fn add_extmark(
+        &mut self,
+        namespace: &str,
+        range: Range<usize>,
+        style: Option<ExtmarkStyle>,
+        data: &str,
+    ) -> usize {
+        let len = self.text.len_chars();
+        let start = range.start.min(len);
+        let id = self.next_extmark;
+        self.next_extmark += 1;
+        self.extmarks.push(Extmark {
+            id,
+            namespace: namespace.to_owned(),
+            range: start..range.end.clamp(start, len),
+            style,
+            data: data.to_owned(),
+        });
+        id
+    }
+
+    pub fn extmark(&self, id: usize) -> Option<&Extmark> {
+        self.extmarks.iter().find(|v| v.id == id)
+    }
+
+    pub fn remove_extmark(&mut self, id: usize) -> Option<Extmark> {
+        let i = self.extmarks.iter().position(|v| v.id == id)?;
+        Some(self.extmarks.remove(i))
+    }
+
+    pub fn clear_namespace(&mut self, namespace: &str) {
+        self.extmarks.retain(|v| v.namespace != namespace);
+    }
+
+    pub fn extmarks(&self) -> impl Iterator<Item = &Extmark> {
+        self.extmarks.iter()
+    }
+
+    // The extmarks that overlap a range of chars, or that are anchored inside it.
+    pub fn extmarks_in(&self, range: Range<usize>) -> impl Iterator<Item = &Extmark> {
+        self.extmarks.iter().filter(move |v| {
+            v.range.start < range.end && range.start < v.range.end
+                || v.range.is_empty() && range.contains(&v.range.start)
+        })
+    }
+
+    pub fn apply_motion(&mut self, motion: Motion, count: Option<usize>) -> bool {
+        if self.hex.is_some() {
+            let step: fn(&mut Buffer) = match motion {
+                Motion::Left => Buffer::move_left,
+                Motion::Right => Buffer::move_right,
+                Motion::Up | Motion::RowUp => Buffer::move_up,
+                Motion::Down | Motion::RowDown => Buffer::move_down,
+                _ => return false,
+            };
+            for _ in 0..count.unwrap_or(1) {
+                step(self);
+            }
+            return true;
+        }
+
+        match self.motion_target(motion, count) {
+            Some(target) => {
+                let desired = match motion {
+                    Motion::Up | Motion::Down => Some(self.desired_col()),
+                    Motion::RowUp | Motion::RowDown if !self.wrap => Some(self.desired_col()),
+                    Motion::LineEnd => Some(DesiredColumn::EndOfLine),
+                    _ => None,
+                };
+                self.set_cursor_index(target);
+                self.desired_col = desired;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Where moving `n` screen rows down, or up if `down` is not set, takes the cursor. It keeps
+    // to the same screen column, or the last char of a row that is too short for it.
+    fn row_target(&self, down: bool, n: usize) -> Option<usize> {
+        let width = self.view_width;
+        let mut line = self.text.char_to_line(self.cursor);
+        let mut rows = self.rows(line, width);
+        let mut r = Buffer::row_index(&rows, self.cursor);
+        let col = rows[r].indent + self.display_width(rows[r].start, self.cursor);
+
+        let mut moved = false;
+        for _ in 0..n {
+            if down && r + 1 < rows.len() {
+                r += 1;
+            } else if down && line + 1 < self.line_count() {
+                line += 1;
+                rows = self.rows(line, width);
+                r = 0;
+            } else if !down && r > 0 {
+                r -= 1;
+            } else if !down && line > 0 {
+                line -= 1;
+                rows = self.rows(line, width);
+                r = rows.len() - 1;
+            } else {
+                break;
+            }
+            moved = true;
+        }
+        if !moved {
+            return None;
+        }
+
+        let row = rows[r];
+        let slice = self.text.slice(..);
+        let mut i = row.start;
+        let mut x = row.indent;
+        while i < row.end {
+            let next = grapheme::next_boundary(&slice, i).min(row.end);
+            x += grapheme::width(&slice.slice(i..next));
+            if x > col || next == row.end && r + 1 < rows.len() {
+                break;
+            }
+            i = next;
+        }
+        Some(i)
+    }
+
+    fn desired_col(&self) -> DesiredColumn {
+        self.desired_col.unwrap_or_else(|| DesiredColumn::Column(self.line_col().1))
+    }
+
+    // Where moving up or down to `line` puts the cursor.
+    fn desired_target(&self, line: usize) -> usize {
+        match self.desired_col() {
+            DesiredColumn::Column(col) => self.line_col_to_char(line, col),
+            DesiredColumn::EndOfLine => {
+                let start = self.text.line_to_char(line);
+                let end = start + self.line_len(line);
+                if end > start {
+                    grapheme::prev_boundary(&self.text.slice(..), end)
+                } else {
+                    start
+                }
+            }
+        }
+    }
+
+    fn move_vertically(&mut self, line: usize) {
+        let desired = self.desired_col();
+        self.set_cursor_index(self.desired_target(line));
+        self.desired_col = Some(desired);
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(hex) = self.hex.as_mut() {
+            hex.prev_nibble();
+        } else if self.cursor > 0 {
+            let before = self.cursor;
+            let at = grapheme::prev_boundary(&self.text.slice(..), self.cursor);
+            let text = self.text.slice(at..self.cursor).to_string();
+            self.delete_raw(at, &text);
+            self.set_cursor_index(at);
+            self.record(before, Edit::Delete { at, text });
+        }
+    }
+
+    pub fn enter(&mut self) {
+        if self.hex.is_none() {
+            self.char('\n');
+        }
+    }
+
+    pub fn char(&mut self, c: char) {
+        if let Some(hex) = self.hex.as_mut() {
+            if hex.set_nibble(c) {
+                self.modified = true;
+            }
+            return;
+        }
+
+        let at = self.cursor;
+        let text = c.to_string();
+        self.insert_raw(at, &text);
+        self.set_cursor_index(at + 1);
+        self.record(at, Edit::Insert { at, text });
+    }
+
+    // Closes the current transaction, so that the edits made since the last commit are undone and
+    // redone as a single unit.
+    pub fn commit(&mut self) {
+        self.history.commit(self.cursor);
+        self.update_modified();
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.commit();
+        match self.history.undo_target() {
+            Some(target) => self.goto_undo_state(target),
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.commit();
+        match self.history.redo_target() {
+            Some(target) => self.goto_undo_state(target),
+            None => false,
+        }
+    }
+
+    pub fn earlier(&mut self, count: usize) -> bool {
+        self.commit();
+        let target = self.history.seq_target(-(count as isize));
+        self.goto_undo_state(target)
+    }
+
+    pub fn later(&mut self, count: usize) -> bool {
+        self.commit();
+        let target = self.history.seq_target(count as isize);
+        self.goto_undo_state(target)
+    }
+
+    pub fn earlier_by(&mut self, duration: Duration) -> bool {
+        self.commit();
+        let target = self.history.time_target(duration, false);
+        self.goto_undo_state(target)
+    }
+
+    pub fn later_by(&mut self, duration: Duration) -> bool {
+        self.commit();
+        let target = self.history.time_target(duration, true);
+        self.goto_undo_state(target)
+    }
+
+    pub fn goto_undo_state(&mut self, seq: usize) -> bool {
+        self.commit();
+        if seq > self.history.last() {
+            return false;
+        }
+
+        let steps = self.history.path(seq);
+        for step in steps.iter() {
+            match *step {
+                Step::Undo(seq) => {
+                    let edits = self.history.node(seq).edits.clone();
+                    for edit in edits.iter().rev() {
+                        self.apply_edit(edit, true);
+                    }
+                }
+
+                Step::Redo(seq) => {
+                    let edits = self.history.node(seq).edits.clone();
+                    for edit in edits.iter() {
+                        self.apply_edit(edit, false);
+                    }
+                }
+            }
+            self.history.apply_step(step);
+        }
+
+        match steps.last() {
+            Some(Step::Undo(seq)) => {
+                self.set_cursor_index(self.history.node(*seq).cursor_before);
+            }
+
+            Some(Step::Redo(seq)) => {
+                self.set_cursor_index(self.history.node(*seq).cursor_after);
+            }
+
+            None => return false,
+        }
+
+        self.update_modified();
+        true
+    }
+
+    pub fn undo_tree(&self) -> &UndoTree {
+        &self.history
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.commit();
+        self.history.mark_saved();
+        self.disk_format = self.file_format();
+        self.modified = false;
+    }
+
+    pub fn start_selection(&mut self, kind: SelectionKind) {
+        self.selection = Some(Selection {
+            kind,
+            anchor: self.cursor,
+            cursor: self.cursor,
+        });
+    }
+
+    pub fn set_selection_kind(&mut self, kind: SelectionKind) {
+        if let Some(selection) = self.selection.as_mut() {
+            selection.kind = kind;
+        }
+    }
+
+    // Moves the cursor to the other end of the selection.
+    pub fn swap_selection_ends(&mut self) {
+        if let Some(selection) = self.selection {
+            self.set_cursor_index(selection.anchor);
+            self.selection = Some(Selection {
+                anchor: selection.cursor,
+                ..selection
+            });
+        }
+    }
+
+    // Grows the selection to take in a text object. When the object at the cursor is already
+    // selected, this takes the one around it for brackets and tags and the next one otherwise.
+    pub fn select_text_object(&mut self, object: TextObject, count: usize) {
+        let selection = match self.selection() {
+            Some(v) => v,
+            None => return,
+        };
+        let slice = self.text.slice(..);
+        let (start, end) = (
+            selection.anchor.min(selection.cursor),
+            selection.anchor.max(selection.cursor),
+        );
+
+        let found = if start == end {
+            object.select(&slice, self.cursor, count, &self.word_chars)
+        } else if object.nests() {
+            (count..)
+                .map(|count| object.select(&slice, self.cursor, count, &self.word_chars))
+                .take_while(Option::is_some)
+                .flatten()
+                .find(|v| {
+                    let (new_start, new_end) = (v.anchor.min(v.cursor), v.anchor.max(v.cursor));
+                    new_start <= start && new_end >= end && (new_start, new_end) != (start, end)
+                })
+        } else {
+            let next = match selection.kind {
+                SelectionKind::Line => {
+                    let line = (self.text.char_to_line(end) + 1).min(self.line_count() - 1);
+                    self.text.line_to_char(line)
+                }
+                _ => grapheme::next_boundary(&slice, end),
+            };
+            object.select(&slice, next, count, &self.word_chars)
+        };
+
+        if let Some(found) = found {
+            let kind = match selection.kind {
+                SelectionKind::Char => found.kind,
+                kind => kind,
+            };
+            self.selection = Some(Selection {
+                kind,
+                anchor: start.min(found.anchor),
+                cursor: end.max(found.cursor),
+            });
+            self.set_cursor_index(end.max(found.cursor));
+        }
+    }
+
+    pub fn clear_selection(&mut self) {
+        if let Some(selection) = self.selection() {
+            self.last_selection = Some(selection);
+        }
+        self.selection = None;
+    }
+
+    // The selection visual mode was last left with, which `'<` and `'>` refer to.
+    pub fn last_selection(&self) -> Option<Selection> {
+        self.last_selection
+    }
+
+    // The current selection, following the cursor as it moves.
+    pub fn selection(&self) -> Option<Selection> {
+        self.selection.map(|v| Selection {
+            cursor: self.cursor,
+            ..v
+        })
+    }
+
+    // The ranges of characters a selection covers, limited to the lines in `lines`. Every
+    // selection is a single range except for blocks, which have one range per line.
+    pub fn selection_ranges(&self, selection: &Selection, lines: Range<usize>) -> Vec<Range<usize>> {
+        let slice = self.text.slice(..);
+        let start = selection.anchor.min(selection.cursor);
+        let end = selection.anchor.max(selection.cursor);
+        let first = self.text.char_to_line(start).max(lines.start);
+        let last = self.text.char_to_line(end).min(lines.end.saturating_sub(1));
+        if first > last {
+            return Vec::new();
+        }
+
+        match selection.kind {
+            SelectionKind::Char => {
+                let end = if end < self.text.len_chars() {
+                    grapheme::next_boundary(&slice, end)
+                } else {
+                    end
+                };
+                let range = start.max(self.text.line_to_char(first))
+                    ..end.min(self.text.line_to_char(last + 1));
+                vec![range]
+            }
+
+            SelectionKind::Line => {
+                let range = self.text.line_to_char(first)..self.text.line_to_char(last + 1);
+                vec![range]
+            }
+
+            SelectionKind::Block => {
+                let (left, right) = self.block_columns(selection);
+                (first..=last)
+                    .filter_map(|line| {
+                        let line_end = self.text.line_to_char(line) + self.line_len(line);
+                        let start = self.line_col_to_char(line, left);
+                        let mut end = self.line_col_to_char(line, right);
+                        if end < line_end {
+                            end = grapheme::next_boundary(&slice, end).min(line_end);
+                        }
+
+                        if start < end {
+                            Some(start..end)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // The text a selection covers. Lines from a line selection always end in a newline, and the
+    // pieces of a block selection are joined by newlines.
+    pub fn selection_text(&self, selection: &Selection) -> String {
+        let ranges = self.selection_ranges(selection, 0..self.line_count());
+        match selection.kind {
+            SelectionKind::Char => self.text.slice(ranges[0].clone()).to_string(),
+
+            SelectionKind::Line => {
+                let mut text = self.text.slice(ranges[0].clone()).to_string();
+                if !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text
+            }
+
+            SelectionKind::Block => ranges
+                .into_iter()
+                .map(|v| self.text.slice(v).to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    // Deletes the selected text, returning what was deleted, and leaves the cursor at the start
+    // of where the selection was.
+    pub fn delete_selection(&mut self, selection: &Selection) -> String {
+        let text = self.selection_text(selection);
+        let mut ranges = self.selection_ranges(selection, 0..self.line_count());
+        if selection.kind == SelectionKind::Line {
+            // Deleting the last line takes the newline before it with it.
+            let range = &mut ranges[0];
+            if range.end == self.text.len_chars() && range.start > 0 {
+                range.start -= 1;
+            }
+        }
+
+        let cursor = self.selection_start(selection);
+        for range in ranges.into_iter().rev() {
+            self.delete_text(range);
+        }
+
+        if selection.kind == SelectionKind::Line {
+            let line = self.text.char_to_line(cursor.min(self.text.len_chars()));
+            self.set_cursor_index(self.text.line_to_char(line));
+        } else {
+            self.set_cursor_index(cursor);
+        }
+        text
+    }
+
+    // `count` lines from the cursor's line down as a line selection.
+    pub fn line_selection(&self, count: usize) -> Selection {
+        let line = (self.text.char_to_line(self.cursor) + count.max(1) - 1).min(self.line_count() - 1);
+        Selection {
+            kind: SelectionKind::Line,
+            anchor: self.cursor,
+            cursor: self.text.line_to_char(line),
+        }
+    }
+
+    // Puts text from a register after or before the cursor. Lines are put below or above the
+    // cursor's line and blocks are put column by column on the lines from the cursor down.
+    pub fn put(&mut self, text: &str, kind: SelectionKind, after: bool) {
+        if self.hex.is_some() || text.is_empty() {
+            return;
+        }
+
+        let (line, col) = self.line_col();
+        let line_end = self.text.line_to_char(line) + self.line_len(line);
+        let next = if after && self.cursor < line_end {
+            grapheme::next_boundary(&self.text.slice(..), self.cursor)
+        } else {
+            self.cursor
+        };
+
+        match kind {
+            SelectionKind::Char => {
+                self.insert_text(next, text);
+                let end = next + text.chars().count();
+                self.set_cursor_index(grapheme::prev_boundary(&self.text.slice(..), end));
+            }
+
+            SelectionKind::Line => {
+                let line = if after { line + 1 } else { line };
+                if line < self.text.len_lines() {
+                    let at = self.text.line_to_char(line);
+                    self.insert_text(at, text);
+                    self.set_cursor_index(at);
+                } else {
+                    // The last line has no newline to put the text after.
+                    let at = self.text.len_chars();
+                    self.insert_text(at, &format!("\n{}", text.strip_suffix('\n').unwrap_or(text)));
+                    self.set_cursor_index(at + 1);
+                }
+            }
+
+            SelectionKind::Block => {
+                let col = col + self.display_width(self.cursor, next);
+                for (i, piece) in text.split('\n').enumerate() {
+                    if line + i >= self.text.len_lines() {
+                        self.insert_text(self.text.len_chars(), "\n");
+                    }
+                    if piece.is_empty() {
+                        continue;
+                    }
+
+                    let start = self.text.line_to_char(line + i);
+                    let end = start + self.line_len(line + i);
+                    let width = self.display_width(start, end);
+                    let at = if width < col {
+                        self.insert_text(end, &" ".repeat(col - width));
+                        end + col - width
+                    } else {
+                        self.line_col_to_char(line + i, col)
+                    };
+                    self.insert_text(at, piece);
+                }
+                self.set_cursor_index(self.line_col_to_char(line, col));
+            }
+        }
+    }
+
+    // Deletes the selected text ready for replacing it. Changing whole lines leaves an empty line
+    // in their place.
+    pub fn change_selection(&mut self, selection: &Selection) -> String {
+        if selection.kind != SelectionKind::Line {
+            return self.delete_selection(selection);
+        }
+
+        let text = self.selection_text(selection);
+        let range = self.selection_ranges(selection, 0..self.line_count())[0].clone();
+        let end = if range.end > range.start && self.text.char(range.end - 1) == '\n' {
+            range.end - 1
+        } else {
+            range.end
+        };
+        self.delete_text(range.start..end);
+        self.set_cursor_index(range.start);
+        text
+    }
+
+    pub fn indent_selection(&mut self, selection: &Selection, outdent: bool) {
+        let first = self.text.char_to_line(selection.anchor.min(selection.cursor));
+        let last = self.text.char_to_line(selection.anchor.max(selection.cursor));
+        for line in first..=last {
+            let start = self.text.line_to_char(line);
+            if outdent {
+                let indent: String = self
+                    .text
+                    .line(line)
+                    .chars()
+                    .take(INDENT.len())
+                    .take_while(|&c| c == ' ' || c == '\t')
+                    .collect();
+                let indent = match indent.find('\t') {
+                    Some(i) => i + 1,
+                    None => indent.len(),
+                };
+                self.delete_text(start..start + indent);
+            } else if self.line_len(line) > 0 {
+                self.insert_text(start, INDENT);
+            }
+        }
+
+        let line = self.text.char_to_line(self.selection_start(selection));
+        self.set_cursor_index(self.text.line_to_char(line));
+    }
+
+    // Without a way to work out the indentation a language wants, lines are indented like vim
+    // does with `autoindent`: each one gets the indentation of the line above it.
+    pub fn reindent_selection(&mut self, selection: &Selection) {
+        let first = self.text.char_to_line(selection.anchor.min(selection.cursor));
+        let last = self.text.char_to_line(selection.anchor.max(selection.cursor));
+        let indent_len = |text: &Rope, line: usize| {
+            text.line(line).chars().take_while(|&c| c == ' ' || c == '\t').count()
+        };
+
+        for line in first..=last {
+            let indent: String = match (0..line).rev().find(|&v| self.line_len(v) > 0) {
+                Some(above) => self.text.line(above).chars().take(indent_len(&self.text, above)).collect(),
+                None => String::new(),
+            };
+
+            let start = self.text.line_to_char(line);
+            let len = indent_len(&self.text, line);
+            if self.line_len(line) > len && self.text.slice(start..start + len) != indent.as_str() {
+                self.replace(start..start + len, &indent);
+            }
+        }
+
+        let line = self.text.char_to_line(self.selection_start(selection));
+        self.set_cursor_index(motion::first_non_blank(&self.text.slice(..), line));
+    }
+
+    pub fn change_case_selection(&mut self, selection: &Selection, case: Case) {
+        for range in self.selection_ranges(selection, 0..self.line_count()) {
+            let text = self.text.slice(range.clone()).to_string();
+            let changed: String = match case {
+                Case::Lower => text.to_lowercase(),
+                Case::Upper => text.to_uppercase(),
+                Case::Toggle => text
+                    .chars()
+                    .flat_map(|c| {
+                        if c.is_uppercase() {
+                            c.to_lowercase().collect::<Vec<_>>()
+                        } else {
+                            c.to_uppercase().collect()
+                        }
+                    })
+                    .collect(),
+            };
+
+            if changed != text {
+                self.delete_text(range.clone());
+                self.insert_text(range.start, &changed);
+            }
+        }
+
+        let cursor = self.selection_start(selection);
+        self.set_cursor_index(cursor);
+    }
+
+    // Where the cursor ends up after an operator is applied to a selection.
+    fn selection_start(&self, selection: &Selection) -> usize {
+        let start = selection.anchor.min(selection.cursor);
+        if selection.kind == SelectionKind::Block {
+            let (left, _) = self.block_columns(selection);
+            self.line_col_to_char(self.text.char_to_line(start), left)
+        } else {
+            start
+        }
+    }
+
+    // The leftmost and rightmost display columns of a block selection.
+    fn block_columns(&self, selection: &Selection) -> (usize, usize) {
+        let column = |i: usize| {
+            let line = self.text.char_to_line(i);
+            self.display_width(self.text.line_to_char(line), i)
+        };
+        let anchor = column(selection.anchor);
+        let cursor = column(selection.cursor);
+        (anchor.min(cursor), anchor.max(cursor))
+    }
+
+    // Inserts text and records it in the history.
+    fn insert_text(&mut self, at: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+
+        let before = self.cursor;
+        self.insert_raw(at, text);
+        self.record(before, Edit::Insert { at, text: text.to_owned() });
+    }
+
+    // Deletes text and records it in the history.
+    fn delete_text(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let before = self.cursor;
+        let text = self.text.slice(range.clone()).to_string();
+        self.delete_raw(range.start, &text);
+        self.cursor = self.cursor.min(self.text.len_chars());
+        self.record(before, Edit::Delete { at: range.start, text });
+    }
+
+    fn file_format(&self) -> FileFormat {
+        FileFormat {
+            line_ending: self.line_ending,
+            mixed_endings: self.mixed_endings,
+            final_newline: self.final_newline,
+            encoding: self.encoding,
+            bom: self.bom,
+        }
+    }
+
+    fn update_modified(&mut self) {
+        self.modified = self.history.is_modified()
+            || self.disk_format != self.file_format()
+            || self.hex.as_ref().map(|v| v.dirty).unwrap_or(false);
+    }
+
+    fn record(&mut self, cursor_before: usize, edit: Edit) {
+        self.history.record(cursor_before, edit);
+        self.modified = true;
+    }
+
+    fn apply_edit(&mut self, edit: &Edit, reverse: bool) {
+        match (edit, reverse) {
+            (Edit::Insert { at, text }, false) | (Edit::Delete { at, text }, true) => {
+                self.insert_raw(*at, text);
+            }
+
+            (Edit::Insert { at, text }, true) | (Edit::Delete { at, text }, false) => {
+                self.delete_raw(*at, text);
+            }
+        }
+    }
+
+    // Inserts text without recording it.
+    fn insert_raw(&mut self, at: usize, text: &str) {
+        self.text.insert(at, text);
+        let len = text.chars().count();
+        for mark in self.marks.values_mut().filter(|v| **v >= at) {
+            *mark += len;
+        }
+        for extmark in self.extmarks.iter_mut() {
+            let range = &mut extmark.range;
+            let anchor = range.start == range.end;
+            if range.start >= at {
+                range.start += len;
+            }
+            if range.end > at || anchor && range.end == at {
+                range.end += len;
+            }
+        }
+
+        self.version += 1;
+        if !self.subscribers.is_empty() {
+            self.emit(BufferEvent::Inserted {
+                version: self.version,
+                range: self.char_to_position(at)..self.char_to_position(at + len),
+                text: text.to_owned(),
+            });
+        }
+    }
+
+    // Deletes text starting at `at` without recording it. Marks in the deleted text move to
+    // where it was.
+    fn delete_raw(&mut self, at: usize, text: &str) {
+        let len = text.chars().count();
+        let range = self.char_to_position(at)..self.char_to_position(at + len);
+        self.text.remove(at..at + len);
+        let shift = |v: &mut usize| {
+            if *v >= at + len {
+                *v -= len;
+            } else if *v > at {
+                *v = at;
+            }
+        };
+        for mark in self.marks.values_mut() {
+            shift(mark);
+        }
+        for extmark in self.extmarks.iter_mut() {
+            shift(&mut extmark.range.start);
+            shift(&mut extmark.range.end);
+        }
+        // The window may not be drawn again before the next motion that looks at it.
+        self.vscroll = self.vscroll.min(self.line_count() - 1);
+
+        self.version += 1;
+        if !self.subscribers.is_empty() {
+            self.emit(BufferEvent::Deleted {
+                version: self.version,
+                range,
+                text: text.to_owned(),
+            });
+        }
+    }
+
+    // The cursor's line and the display column it is on.
+    fn line_col(&self) -> (usize, usize) {
+        let line = self.text.char_to_line(self.cursor);
+        (line, self.display_width(self.text.line_to_char(line), self.cursor))
+    }
+
+    fn display_width(&self, start: usize, end: usize) -> usize {
+        let slice = self.text.slice(..);
+        let mut i = start;
+        let mut width = 0;
+        while i < end {
+            let next = grapheme::next_boundary(&slice, i);
+            width += grapheme::width(&slice.slice(i..next));
+            i = next;
+        }
+        width
+    }
+
+    // The start of the grapheme cluster covering display column `col` in `line`, or the end of
+    // the line if it is shorter than that.
+    fn line_col_to_char(&self, line: usize, col: usize) -> usize {
+        let slice = self.text.slice(..);
+        let mut i = self.text.line_to_char(line);
+        let end = i + self.line_len(line);
+        let mut x = 0;
+        while i < end {
+            let next = grapheme::next_boundary(&slice, i).min(end);
+            x += grapheme::width(&slice.slice(i..next));
+            if x > col {
+                break;
+            }
+            i = next;
+        }
+        i
+    }
+
+    fn line_len(&self, line: usize) -> usize {
+        let line = self.text.line(line);
+        match line.len_chars() {
+            0 => 0,
+            len if line.char(len - 1) == '\n' => len - 1,
+            len => len,
+        }
+    }
+
+    pub fn set_cursor_index(&mut self, cursor: usize) {
+        self.cursor = cursor.min(self.text.len_chars());
+        self.desired_col = None;
+        self.update_vscroll = true;
+        self.update_hscroll = true;
+    }
+
+    // The screen rows line `line` is shown on when the window is `width` columns wide. A line
+    // that does not fit is broken after the last space before the edge where there is one, and
+    // the rows that continue it are indented as far as the line is, unless that would leave less
+    // than half the window for them.
+    fn rows(&self, line: usize, width: usize) -> Vec<Row> {
+        let slice = self.text.slice(..);
+        let start = self.text.line_to_char(line);
+        let end = start + self.line_len(line);
+        if !self.wrap || width == 0 {
+            return vec![Row { start, end, indent: 0 }];
+        }
+
+        let mut indent = self.display_width(start, motion::first_non_blank(&slice, line).min(end));
+        if indent * 2 >= width {
+            indent = 0;
+        }
+
+        let mut rows = Vec::new();
+        let mut row = Row { start, end, indent: 0 };
+        let mut x = 0;
+        let mut break_at = None;
+        let mut i = start;
+        while i < end {
+            let next = grapheme::next_boundary(&slice, i).min(end);
+            let c = slice.char(i);
+
+            // Spaces at the edge hang past it rather than starting the next row.
+            if x + grapheme::width(&slice.slice(i..next)) > width - row.indent
+                && i > row.start
+                && !c.is_whitespace()
+            {
+                let at = break_at.filter(|&v| v > row.start).unwrap_or(i);
+                rows.push(Row { end: at, ..row });
+                row = Row { start: at, end, indent };
+                x = self.display_width(at, i);
+                break_at = None;
+                continue;
+            }
+
+            if c.is_whitespace() && next < end && !slice.char(next).is_whitespace() {
+                break_at = Some(next);
+            }
+            x += grapheme::width(&slice.slice(i..next));
+            i = next;
+        }
+        rows.push(row);
+        rows
+    }
+
+    // The index of the row in `rows` that `i` is shown on.
+    fn row_index(rows: &[Row], i: usize) -> usize {
+        rows.iter().rposition(|v| v.start <= i).unwrap_or(0)
+    }
+
+    // How many lines from the top of the window are shown in full.
+    fn visible_lines(&self) -> usize {
+        let lines = self.line_count().saturating_sub(self.vscroll);
+        if !self.wrap {
+            return self.view_height.min(lines);
+        }
+
+        let mut rows = 0;
+        (self.vscroll..self.line_count())
+            .take_while(|&line| {
+                rows += self.rows(line, self.view_width).len();
+                rows <= self.view_height
+            })
+            .count()
+    }
+
+    pub fn update_scrolls(&mut self, width: isize, height: isize) {
+        if let Some(hex) = self.hex.as_mut() {
+            hex.update_scroll(height as usize);
+            return;
+        }
+
+        self.view_width = width as usize;
+        self.view_height = height as usize;
+        let (line, col) = self.line_col();
+        if self.wrap {
+            self.hscroll = 0;
+            self.update_hscroll = false;
+            if self.update_vscroll {
+                self.update_vscroll = false;
+                self.scroll_to_row(line, height as usize);
+            }
+            return;
+        }
+
+        if self.update_vscroll {
+            self.update_vscroll = false;
+
+            if line as isize - (self.vscroll as isize) >= height {
+                self.vscroll = line + 1 - height as usize;
+            } else if line < self.vscroll {
+                self.vscroll = line;
+            }
+        }
+
+        if self.update_hscroll {
+            self.update_hscroll = false;
+
+            let slice = self.text.slice(..);
+            let next = grapheme::next_boundary(&slice, self.cursor);
+            let cursor_width = grapheme::width(&slice.slice(self.cursor..next)).max(1);
+            if (col + cursor_width) as isize - (self.hscroll as isize) > width {
+                self.hscroll = (col + cursor_width).saturating_sub(width as usize);
+            } else if col < self.hscroll {
+                self.hscroll = col;
+            }
+        }
+    }
+
+    pub fn cursor_pos(&self, x: usize, y: usize) -> (usize, usize) {
+        if let Some(hex) = self.hex.as_ref() {
+            return hex.cursor_pos(x, y);
+        }
+
+        let (line, col) = self.line_col();
+        if !self.wrap {
+            return (x + col - self.hscroll, y + line - self.vscroll);
+        }
+
+        let width = self.view_width;
+        let rows = self.rows(line, width);
+        let r = Buffer::row_index(&rows, self.cursor);
+        let above: usize = (self.vscroll..line).map(|v| self.rows(v, width).len()).sum();
+        let col = rows[r].indent + self.display_width(rows[r].start, self.cursor);
+        (x + col.min(width.saturating_sub(1)), y + above + r)
+    }
+
+    // Scrolls a wrapped window as little as it takes to show the cursor's row.
+    fn scroll_to_row(&mut self, line: usize, height: usize) {
+        if line < self.vscroll {
+            self.vscroll = line;
+            return;
+        }
+
+        // Every line takes at least one row, so lines further up than the height are gone anyway.
+        if line - self.vscroll >= height {
+            self.vscroll = line + 1 - height.max(1);
+        }
+        let width = self.view_width;
+        let cursor_rows = Buffer::row_index(&self.rows(line, width), self.cursor) + 1;
+        let mut rows: usize = cursor_rows + (self.vscroll..line).map(|v| self.rows(v, width).len()).sum::<usize>();
+        while rows > height && self.vscroll < line {
+            rows -= self.rows(self.vscroll, width).len();
+            self.vscroll += 1;
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.text.len_lines()
+    }
+}
+
+pub struct BufferWindow<'a> {
+    buffer: &'a Buffer,
+    i: usize,
+    width: usize,
+    height: usize,
+    highlights: Vec<(Range<usize>, Highlight)>,
+    // With wrapping on, the next line to show and the rows of the current one still to come.
+    line: usize,
+    rows: VecDeque<Row>,
+}
+
+impl BufferWindow<'_> {
+    pub fn highlight(mut self, range: Range<usize>, highlight: Highlight) -> Self {
+        self.highlights.push((range, highlight));
+        self
+    }
+
+    // Highlights every match of `regex` that starts on a visible line. Matches are looked for in
+    // the screenful of lines after the visible ones as well, so that those running off the bottom
+    // are still found.
+    pub fn search(mut self, regex: Option<&Regex>) -> Self {
+        if let Some(regex) = regex {
+            let text = &self.buffer.text;
+            let line = |v: usize| text.line_to_char(v.min(text.len_lines()));
+            let start = line(self.buffer.vscroll);
+            let end = line(self.buffer.vscroll + self.height);
+            self.highlights.extend(
+                search::matches(text, regex, start..line(self.buffer.vscroll + self.height * 2))
+                    .into_iter()
+                    .take_while(|v| v.start < end)
+                    .map(|v| (v, Highlight::Search)),
+            );
+        }
+        self
+    }
+
+    // Highlights the visible extmarks that have a style, under everything highlighted so far.
+    pub fn extmarks(mut self) -> Self {
+        let text = &self.buffer.text;
+        let line = |v: usize| text.line_to_char(v.min(text.len_lines()));
+        let visible = line(self.buffer.vscroll)..line(self.buffer.vscroll + self.height);
+        self.highlights.extend(
+            self.buffer
+                .extmarks_in(visible)
+                .filter_map(|v| Some((v.range.clone(), Highlight::Extmark(v.style?)))),
+        );
+        self
+    }
+
+    fn highlight_at(&self, i: usize) -> Highlight {
+        self.highlights
+            .iter()
+            .find(|(range, _)| range.contains(&i))
+            .map(|&(_, highlight)| highlight)
+            .unwrap_or(Highlight::None)
+    }
+
+    // The chars from `start` to `stop`, split up wherever their highlight changes.
+    fn spans<'a>(&self, slice: RopeSlice<'a>, start: usize, stop: usize) -> Vec<(Cow<'a, str>, Highlight)> {
+        let mut bounds = vec![start, stop];
+        for (range, _) in self.highlights.iter() {
+            bounds.extend([range.start, range.end].into_iter().filter(|&v| start < v && v < stop));
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut spans = Vec::new();
+        for v in bounds.windows(2) {
+            let highlight = self.highlight_at(v[0]);
+            spans.extend(slice.slice(v[0]..v[1]).chunks().map(|v| (Cow::Borrowed(v), highlight)));
+        }
+        spans
+    }
+}
+
+impl<'a> BufferWindow<'a> {
+    // Yields the next screen row of wrapped lines.
+    fn next_row(&mut self) -> Option<Vec<(Cow<'a, str>, Highlight)>> {
+        if self.i >= self.height {
+            return None;
+        }
+        if self.rows.is_empty() {
+            if self.line >= self.buffer.line_count() {
+                return None;
+            }
+            self.rows = self.buffer.rows(self.line, self.width).into();
+            self.line += 1;
+        }
+        self.i += 1;
+
+        let row = self.rows.pop_front().unwrap();
+        let mut line = Vec::new();
+        if row.indent > 0 {
+            line.push((Cow::Owned(" ".repeat(row.indent)), Highlight::None));
+        }
+        line.extend(self.spans(self.buffer.text.slice(..), row.start, row.end));
+
+        let highlight = self.highlight_at(row.end);
+        let x = row.indent + self.buffer.display_width(row.start, row.end);
+        if self.rows.is_empty() && x < self.width && highlight != Highlight::None {
+            line.push((Cow::Borrowed(" "), highlight));
+        }
+        Some(line)
+    }
+}
+
+impl<'a> Iterator for BufferWindow<'a> {
+    type Item = Vec<(Cow<'a, str>, Highlight)>;
+
+    // Yields the visible part of each line. A wide character cut off by either edge of the
+    // window is replaced by spaces so that the rest of the line stays in its column.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.wrap {
+            return self.next_row();
+        }
+
+        if self.i < self.height {
+            let i = self.i + self.buffer.vscroll;
+            self.i += 1;
+            if i >= self.buffer.line_count() {
+                return None;
+            }
+
+            let slice = self.buffer.text.slice(..);
+            let left = self.buffer.hscroll;
+            let right = self.buffer.hscroll + self.width;
+            let mut j = self.buffer.text.line_to_char(i);
+            let end = j + self.buffer.line_len(i);
+            let mut x = 0;
+            let mut start = None;
+            let mut stop = end;
+            let mut pad_left = 0;
+            let mut pad_right = 0;
+            while j < end {
+                let next = grapheme::next_boundary(&slice, j).min(end);
+                let width = grapheme::width(&slice.slice(j..next));
+                if start.is_none() && x < left {
+                    if x + width > left {
+                        pad_left = (x + width - left).min(self.width);
+                        start = Some(next);
+                    }
+                    x += width;
+                    j = next;
+                    continue;
+                }
+
+                start.get_or_insert(j);
+                if x + width > right {
+                    pad_right = right.saturating_sub(x);
+                    stop = j;
+                    break;
+                }
+
+                x += width;
+                j = next;
+            }
+
+            let start = start.unwrap_or(end).min(stop);
+            let mut line = Vec::new();
+            if pad_left > 0 {
+                line.push((Cow::Owned(" ".repeat(pad_left)), Highlight::None));
+            }
+
+            line.extend(self.spans(slice, start, stop));
+
+            if pad_right > 0 {
+                line.push((Cow::Owned(" ".repeat(pad_right)), Highlight::None));
+            }
+
+            // A highlighted newline is shown as a space so that selected empty lines are visible.
+            let highlight = self.highlight_at(end);
+            if stop == end && left <= x && x < right && highlight != Highlight::None {
+                line.push((Cow::Borrowed(" "), highlight));
+            }
+            Some(line)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_endings_round_trip() {
+        for text in ["a\nb\n", "a\r\nb\r\n", "a\rb\r", "a\r\nb"] {
+            let buffer = Buffer::new("test", false, text);
+            assert!(!buffer.mixed_endings());
+            assert_eq!(buffer.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn mixed_line_endings() {
+        let mut buffer = Buffer::new("test", true, "a\r\nb\nc\r\n");
+        assert!(buffer.mixed_endings());
+        assert_eq!(buffer.line_count(), 3);
+        assert!(buffer.write().is_err());
+        assert!(!buffer.modified);
+
+        buffer.set_line_ending(LineEnding::CrLf);
+        assert!(!buffer.mixed_endings());
+        assert!(buffer.modified);
+        assert_eq!(buffer.to_string(), "a\r\nb\r\nc\r\n");
+    }
+
+    #[test]
+    fn crlf_in_a_cr_file_is_one_line_break() {
+        let buffer = Buffer::new("test", false, "a\rb\r\nc\rd\r");
+        assert!(buffer.line_ending() == LineEnding::Cr);
+        assert_eq!(buffer.text().to_string(), "a\nb\nc\nd");
+    }
+
+    #[test]
+    fn cursor_as_line_and_column() {
+        let mut buffer = Buffer::new("test", false, "hello\nworld\n");
+        buffer.set_cursor(1, 3);
+        assert_eq!(buffer.cursor(), (1, 3));
+        assert_eq!(buffer.cursor_index(), 9);
+        buffer.set_cursor(0, 99);
+        assert_eq!(buffer.cursor(), (0, 5));
+        buffer.set_cursor(9, 0);
+        assert_eq!(buffer.cursor(), (1, 5));
+    }
+
+    #[test]
+    fn edit_by_position() {
+        let mut buffer = Buffer::new("test", false, "hello world\nsecond\n");
+        buffer.set_cursor(1, 2);
+        buffer.insert_str_at(Position { line: 0, col: 5 }, ",\nnew");
+        assert_eq!(buffer.cursor(), (2, 2));
+        assert_eq!(buffer.lines().map(|v| v.to_string()).collect::<Vec<_>>(), ["hello,", "new world", "second"]);
+
+        let range = Position { line: 1, col: 0 }..Position { line: 1, col: 3 };
+        assert_eq!(buffer.text_in_range(range.clone()), "new");
+        assert_eq!(buffer.replace_range(range, "old"), "new");
+        assert_eq!(buffer.line(1).unwrap(), "old world");
+
+        let deleted = buffer.delete_range(Position { line: 2, col: 0 }..Position { line: 0, col: 6 });
+        assert_eq!(deleted, "\nold world\n");
+        assert_eq!(buffer.to_string(), "hello,second\n");
+        assert_eq!(buffer.cursor(), (0, 8));
+        assert!(buffer.line(2).is_none());
+
+        buffer.commit();
+        buffer.undo();
+        assert_eq!(buffer.to_string(), "hello world\nsecond\n");
+    }
+
+    #[test]
+    fn screen_motions_after_deleting_scrolled_lines() {
+        let text = "line\n".repeat(100);
+        let mut buffer = Buffer::new("test", false, &text);
+        buffer.apply_motion(Motion::LastLine, None);
+        buffer.update_scrolls(80, 10);
+        assert_eq!(buffer.vscroll, 90);
+
+        buffer.delete_range(Position { line: 4, col: 4 }..Position { line: 100, col: 0 });
+        assert!(buffer.apply_motion(Motion::ScreenTop, None));
+        assert!(buffer.apply_motion(Motion::ScreenBottom, None));
+        assert_eq!(buffer.cursor().0, 4);
+    }
+
+    #[test]
+    fn desired_column() {
+        let mut buffer = Buffer::new("test", false, "a long line
+ab
+
+another line
+");
+        buffer.set_cursor(0, 7);
+        buffer.apply_motion(Motion::Down, None);
+        assert_eq!(buffer.cursor(), (1, 2));
+        buffer.apply_motion(Motion::Down, None);
+        assert_eq!(buffer.cursor(), (2, 0));
+        buffer.apply_motion(Motion::Down, None);
+        assert_eq!(buffer.cursor(), (3, 7));
+
+        // Any other motion sets a new column.
+        buffer.apply_motion(Motion::Left, None);
+        buffer.apply_motion(Motion::Up, Some(2));
+        assert_eq!(buffer.cursor(), (1, 2));
+        buffer.apply_motion(Motion::Up, None);
+        assert_eq!(buffer.cursor(), (0, 6));
+
+        buffer.apply_motion(Motion::LineEnd, None);
+        buffer.apply_motion(Motion::Down, None);
+        assert_eq!(buffer.cursor(), (1, 1));
+        buffer.apply_motion(Motion::Down, Some(2));
+        assert_eq!(buffer.cursor(), (3, 11));
+    }
+
+    #[test]
+    fn undo_groups_edits_until_commit() {
+        let mut buffer = Buffer::new("test", false, "one\n");
+        buffer.insert_str_at(Position { line: 0, col: 3 }, " two");
+        buffer.insert_str_at(Position { line: 0, col: 7 }, " three");
+        buffer.commit();
+        buffer.delete_range(Position { line: 0, col: 0 }..Position { line: 0, col: 4 });
+        buffer.commit();
+        assert_eq!(buffer.to_string(), "two three\n");
+
+        assert!(buffer.undo());
+        assert_eq!(buffer.to_string(), "one two three\n");
+        assert!(buffer.undo());
+        assert_eq!(buffer.to_string(), "one\n");
+        assert!(!buffer.modified);
+        assert!(!buffer.undo());
+
+        assert!(buffer.redo());
+        assert_eq!(buffer.to_string(), "one two three\n");
+        assert!(buffer.modified);
+    }
+
+    #[test]
+    fn change_events() {
+        let mut buffer = Buffer::new("test", false, "ab\ncd\n");
+        let events = buffer.subscribe();
+        let version = buffer.version();
+        buffer.insert_str_at(Position { line: 1, col: 1 }, "x\ny");
+        buffer.delete_range(Position { line: 0, col: 1 }..Position { line: 1, col: 0 });
+        buffer.commit();
+        buffer.undo();
+
+        let position = |line, col| Position { line, col };
+        let text = String::from;
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                BufferEvent::Inserted { version: version + 1, range: position(1, 1)..position(2, 1), text: text("x\ny") },
+                BufferEvent::Deleted { version: version + 2, range: position(0, 1)..position(1, 0), text: text("b\n") },
+                BufferEvent::Inserted { version: version + 3, range: position(0, 1)..position(1, 0), text: text("b\n") },
+                BufferEvent::Deleted { version: version + 4, range: position(1, 1)..position(2, 1), text: text("x\ny") },
+            ]
+        );
+        assert_eq!(buffer.version(), version + 4);
+    }
+
+    #[test]
+    fn extmarks_move_with_the_text() {
+        let mut buffer = Buffer::new("test", false, "hello world\n");
+        let word = buffer.add_extmark("test", 6..11, Some(ExtmarkStyle::Error), "data");
+        let anchor = buffer.add_extmark("test", 11..11, None, "");
+        let other = buffer.add_extmark("other", 0..5, None, "");
+        let range = |buffer: &Buffer, id| buffer.extmark(id).unwrap().range.clone();
+
+        // Text inserted at either edge stays outside, and text inserted inside grows the range.
+        buffer.insert_str_at(Position { line: 0, col: 6 }, "big ");
+        assert_eq!(range(&buffer, word), 10..15);
+        buffer.insert_str_at(Position { line: 0, col: 15 }, "!");
+        assert_eq!(range(&buffer, word), 10..15);
+        assert_eq!(range(&buffer, anchor), 16..16);
+        buffer.insert_str_at(Position { line: 0, col: 12 }, "--");
+        assert_eq!(range(&buffer, word), 10..17);
+
+        buffer.delete_range(Position { line: 0, col: 8 }..Position { line: 0, col: 12 });
+        assert_eq!(range(&buffer, word), 8..13);
+        assert_eq!(buffer.extmark(word).unwrap().data, "data");
+
+        let ids = |range| buffer.extmarks_in(range).map(|v| v.id).collect::<Vec<_>>();
+        assert_eq!(ids(0..8), [other]);
+        assert_eq!(ids(12..20), [word, anchor]);
+
+        buffer.clear_namespace("test");
+        assert!(buffer.extmark(word).is_none());
+        assert_eq!(buffer.remove_extmark(other).unwrap().namespace, "other");
+        assert_eq!(buffer.extmarks().count(), 0);
+    }
+
+    #[test]
+    fn extmarks_are_clamped_to_the_text() {
+        let mut buffer = Buffer::new("test", false, "abc\n");
+        let id = buffer.add_extmark("test", 2..10, None, "");
+        assert_eq!(buffer.extmark(id).unwrap().range, 2..3);
+        let id = buffer.add_extmark("test", 5..9, None, "");
+        assert_eq!(buffer.extmark(id).unwrap().range, 3..3);
+    }
+
+    #[test]
+    fn wrapped_lines() {
+        let mut buffer = Buffer::new("test", false, "aaaa bbbb cccc\n  dd ee ff\nx\n");
+        buffer.set_wrap(true);
+        buffer.update_scrolls(6, 10);
+        assert_eq!(buffer.screen_lines(6, 10), [Some(0), None, None, Some(1), None, None, Some(2)]);
+        assert_eq!(buffer.screen_lines(6, 4), [Some(0), None, None, Some(1)]);
+
+        buffer.set_cursor(0, 11);
+        assert_eq!(buffer.cursor_pos(0, 0), (1, 2));
+        buffer.apply_motion(Motion::RowUp, None);
+        assert_eq!(buffer.cursor(), (0, 6));
+        buffer.apply_motion(Motion::RowDown, Some(2));
+        assert_eq!(buffer.cursor(), (1, 1));
+        // Rows that continue an indented line keep its indent.
+        buffer.apply_motion(Motion::RowDown, None);
+        assert_eq!(buffer.cursor(), (1, 5));
+        assert_eq!(buffer.cursor_pos(0, 0), (2, 4));
+
+        // Without wrapping, rows are lines.
+        buffer.set_wrap(false);
+        buffer.apply_motion(Motion::RowUp, None);
+        assert_eq!(buffer.cursor(), (0, 5));
+        assert_eq!(buffer.screen_lines(6, 10), [Some(0), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn wrapped_scrolling() {
+        let text = "word ".repeat(20) + "\nend\n";
+        let mut buffer = Buffer::new("test", false, &text);
+        buffer.set_wrap(true);
+        buffer.apply_motion(Motion::LastLine, None);
+        buffer.update_scrolls(10, 4);
+        assert_eq!(buffer.vscroll, 1);
+        assert_eq!(buffer.cursor_pos(0, 0), (0, 0));
     }
 }
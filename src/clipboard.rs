@@ -0,0 +1,298 @@
+use std::{
+    env, fmt,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use crossterm::execute;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClipboardTarget {
+    // The `+` register.
+    Clipboard,
+    // The `*` register, which is the primary selection on X11 and Wayland.
+    Primary,
+}
+
+pub trait ClipboardProvider {
+    fn name(&self) -> &str;
+    fn copy(&mut self, target: ClipboardTarget, text: &str) -> io::Result<()>;
+    fn paste(&mut self, target: ClipboardTarget) -> io::Result<Option<String>>;
+}
+
+// Finds the best provider for the current session. Wayland and X11 helpers are preferred when
+// there is a display to talk to, and everything else falls back to OSC 52, which also works over
+// SSH as long as the terminal supports it.
+pub fn detect() -> Box<dyn ClipboardProvider> {
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+        if let Some(provider) = External::wl_copy() {
+            return Box::new(provider);
+        }
+    }
+
+    if env::var_os("DISPLAY").is_some() {
+        if let Some(provider) = External::xclip() {
+            return Box::new(provider);
+        }
+    }
+
+    Box::new(Osc52::new(io::stdout()))
+}
+
+// Looks up a provider by the name used for `:set clipboard`.
+pub fn from_name(name: &str) -> Option<Box<dyn ClipboardProvider>> {
+    match name {
+        "auto" => Some(detect()),
+        "osc52" => Some(Box::new(Osc52::new(io::stdout()))),
+        "wl-copy" => External::wl_copy().map(|v| Box::new(v) as _),
+        "xclip" => External::xclip().map(|v| Box::new(v) as _),
+        "internal" => Some(Box::new(MemoryClipboard::default())),
+        _ => None,
+    }
+}
+
+// Copies by asking the terminal to set the clipboard with an OSC 52 escape sequence. Terminals
+// rarely allow reading the clipboard back, so pasting returns what was last copied from nu.
+pub struct Osc52<W: Write> {
+    out: W,
+    copied: MemoryClipboard,
+    tmux: bool,
+}
+
+impl<W: Write> Osc52<W> {
+    pub fn new(out: W) -> Self {
+        Osc52 {
+            out,
+            copied: MemoryClipboard::default(),
+            tmux: env::var_os("TMUX").is_some(),
+        }
+    }
+}
+
+impl<W: Write> ClipboardProvider for Osc52<W> {
+    fn name(&self) -> &str {
+        "osc52"
+    }
+
+    fn copy(&mut self, target: ClipboardTarget, text: &str) -> io::Result<()> {
+        execute!(
+            self.out,
+            SetClipboard {
+                target,
+                text,
+                tmux: self.tmux,
+            }
+        )?;
+        self.copied.copy(target, text)
+    }
+
+    fn paste(&mut self, target: ClipboardTarget) -> io::Result<Option<String>> {
+        self.copied.paste(target)
+    }
+}
+
+struct SetClipboard<'a> {
+    target: ClipboardTarget,
+    text: &'a str,
+    tmux: bool,
+}
+
+impl crossterm::Command for SetClipboard<'_> {
+    fn write_ansi(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        let target = match self.target {
+            ClipboardTarget::Clipboard => 'c',
+            ClipboardTarget::Primary => 'p',
+        };
+
+        // tmux only passes escape sequences on to the terminal when they are wrapped in its own
+        // passthrough sequence, with every escape inside doubled.
+        if self.tmux {
+            write!(f, "\x1bPtmux;\x1b\x1b]52;{};{}\x07\x1b\\", target, base64(self.text.as_bytes()))
+        } else {
+            write!(f, "\x1b]52;{};{}\x07", target, base64(self.text.as_bytes()))
+        }
+    }
+
+    #[cfg(windows)]
+    fn execute_winapi(&self) -> crossterm::Result<()> {
+        Ok(())
+    }
+}
+
+// Copies and pastes by running helper programs such as `wl-copy` and `xclip`, which read the text
+// to copy from stdin and write pasted text to stdout.
+pub struct External {
+    name: String,
+    copy: [Vec<String>; 2],
+    paste: [Vec<String>; 2],
+}
+
+impl External {
+    // Each command is given as a program followed by its arguments, for the clipboard and then the
+    // primary selection.
+    pub fn new(name: &str, copy: [&[&str]; 2], paste: [&[&str]; 2]) -> Self {
+        let owned = |v: [&[&str]; 2]| v.map(|v| v.iter().map(|&v| v.to_owned()).collect());
+        External {
+            name: name.to_owned(),
+            copy: owned(copy),
+            paste: owned(paste),
+        }
+    }
+
+    pub fn wl_copy() -> Option<Self> {
+        if !in_path("wl-copy") || !in_path("wl-paste") {
+            return None;
+        }
+
+        Some(External::new(
+            "wl-copy",
+            [&["wl-copy"], &["wl-copy", "--primary"]],
+            [&["wl-paste", "--no-newline"], &["wl-paste", "--no-newline", "--primary"]],
+        ))
+    }
+
+    pub fn xclip() -> Option<Self> {
+        if !in_path("xclip") {
+            return None;
+        }
+
+        Some(External::new(
+            "xclip",
+            [&["xclip", "-selection", "clipboard"], &["xclip", "-selection", "primary"]],
+            [
+                &["xclip", "-selection", "clipboard", "-o"],
+                &["xclip", "-selection", "primary", "-o"],
+            ],
+        ))
+    }
+}
+
+impl ClipboardProvider for External {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn copy(&mut self, target: ClipboardTarget, text: &str) -> io::Result<()> {
+        let command = &self.copy[target as usize];
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(text.as_bytes())?;
+
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("`{}` exited with {}", command[0], status)));
+        }
+        Ok(())
+    }
+
+    fn paste(&mut self, target: ClipboardTarget) -> io::Result<Option<String>> {
+        let command = &self.paste[target as usize];
+        let output = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()?;
+
+        // Both helpers fail when there is nothing to paste.
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+}
+
+// Keeps the clipboard in memory. Used when nu should not touch the system clipboard, and as a
+// stand-in for the real thing in tests.
+#[derive(Default)]
+pub struct MemoryClipboard {
+    contents: [Option<String>; 2],
+}
+
+impl ClipboardProvider for MemoryClipboard {
+    fn name(&self) -> &str {
+        "internal"
+    }
+
+    fn copy(&mut self, target: ClipboardTarget, text: &str) -> io::Result<()> {
+        self.contents[target as usize] = Some(text.to_owned());
+        Ok(())
+    }
+
+    fn paste(&mut self, target: ClipboardTarget) -> io::Result<Option<String>> {
+        Ok(self.contents[target as usize].clone())
+    }
+}
+
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .map(|v| env::split_paths(&v).any(|dir| Path::new(&dir).join(program).is_file()))
+        .unwrap_or(false)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn osc52(tmux: bool, target: ClipboardTarget, text: &str) -> String {
+        let mut clipboard = Osc52::new(Vec::new());
+        clipboard.tmux = tmux;
+        clipboard.copy(target, text).unwrap();
+        assert_eq!(clipboard.paste(target).unwrap().as_deref(), Some(text));
+        String::from_utf8(clipboard.out).unwrap()
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(b"abcd"), "YWJjZA==");
+        assert_eq!(base64(b"abcde"), "YWJjZGU=");
+        assert_eq!(base64(b"\xff\xfe\xfd"), "//79");
+    }
+
+    #[test]
+    fn osc52_sequence() {
+        assert_eq!(osc52(false, ClipboardTarget::Clipboard, "hi"), "\x1b]52;c;aGk=\x07");
+        assert_eq!(osc52(false, ClipboardTarget::Primary, "hi"), "\x1b]52;p;aGk=\x07");
+    }
+
+    #[test]
+    fn osc52_sequence_in_tmux() {
+        assert_eq!(
+            osc52(true, ClipboardTarget::Clipboard, "hi"),
+            "\x1bPtmux;\x1b\x1b]52;c;aGk=\x07\x1b\\"
+        );
+    }
+
+    #[test]
+    fn memory_clipboard_targets() {
+        let mut clipboard = MemoryClipboard::default();
+        clipboard.copy(ClipboardTarget::Primary, "a").unwrap();
+        assert_eq!(clipboard.paste(ClipboardTarget::Clipboard).unwrap(), None);
+        assert_eq!(clipboard.paste(ClipboardTarget::Primary).unwrap().as_deref(), Some("a"));
+    }
+}
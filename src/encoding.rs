@@ -0,0 +1,157 @@
+use std::io;
+
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+pub fn lookup(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label_no_replacement(label.as_bytes())
+}
+
+// Guesses the encoding of a file from its byte order mark, falling back to looking at where the
+// NUL bytes are for UTF-16 and then at whether the bytes are valid UTF-8. Anything else is treated
+// as Windows-1252, which decodes every byte and is a superset of Latin-1 for printable text.
+// Returns the encoding and whether the file starts with a byte order mark.
+pub fn detect(bytes: &[u8]) -> (&'static Encoding, bool) {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return (encoding, true);
+    }
+
+    let sample = &bytes[..bytes.len().min(4096)];
+    if sample.len() >= 2 && bytes.len().is_multiple_of(2) {
+        let even = sample.iter().step_by(2).filter(|&&v| v == 0).count();
+        let odd = sample.iter().skip(1).step_by(2).filter(|&&v| v == 0).count();
+        let half = sample.len() / 2;
+        if odd * 10 >= half * 3 && even * 10 < half {
+            return (UTF_16LE, false);
+        } else if even * 10 >= half * 3 && odd * 10 < half {
+            return (UTF_16BE, false);
+        }
+    }
+
+    if std::str::from_utf8(bytes).is_ok() {
+        (UTF_8, false)
+    } else {
+        (WINDOWS_1252, false)
+    }
+}
+
+pub fn decode(bytes: &[u8], encoding: &'static Encoding) -> io::Result<(String, bool)> {
+    let (bytes, bom) = match Encoding::for_bom(bytes) {
+        Some((v, len)) if v == encoding => (&bytes[len..], true),
+        _ => (bytes, false),
+    };
+
+    match encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+        Some(text) => Ok((text.into_owned(), bom)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("file is not valid {}", encoding.name()),
+        )),
+    }
+}
+
+pub fn encode(text: &str, encoding: &'static Encoding, bom: bool) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        if bom {
+            bytes.extend(bom_for(encoding));
+        }
+
+        for unit in text.encode_utf16() {
+            bytes.extend(if encoding == UTF_16LE {
+                unit.to_le_bytes()
+            } else {
+                unit.to_be_bytes()
+            });
+        }
+        return Ok(bytes);
+    }
+
+    let (encoded, _, had_errors) = encoding.encode(text);
+    if had_errors || encoding.output_encoding() != encoding {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("text cannot be encoded as {}", encoding.name()),
+        ));
+    }
+
+    if bom && encoding == UTF_8 {
+        bytes.extend(bom_for(encoding));
+    }
+    bytes.extend_from_slice(&encoded);
+    Ok(bytes)
+}
+
+fn bom_for(encoding: &'static Encoding) -> &'static [u8] {
+    if encoding == UTF_8 {
+        b"\xEF\xBB\xBF"
+    } else if encoding == UTF_16LE {
+        b"\xFF\xFE"
+    } else if encoding == UTF_16BE {
+        b"\xFE\xFF"
+    } else {
+        b""
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::REPLACEMENT;
+
+    use super::*;
+
+    fn utf16(text: &str, little_endian: bool) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|v| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() })
+            .collect()
+    }
+
+    #[test]
+    fn byte_order_marks() {
+        assert_eq!(detect(b"\xEF\xBB\xBFabc"), (UTF_8, true));
+        assert_eq!(detect(b"\xFF\xFEa\0"), (UTF_16LE, true));
+        assert_eq!(detect(b"\xFE\xFF\0a"), (UTF_16BE, true));
+    }
+
+    #[test]
+    fn utf16_without_a_byte_order_mark() {
+        assert_eq!(detect(&utf16("hello\nworld\n", true)), (UTF_16LE, false));
+        assert_eq!(detect(&utf16("hello\nworld\n", false)), (UTF_16BE, false));
+        // NUL bytes that are not laid out like UTF-16 are left to the other checks.
+        assert_eq!(detect(b"a\0\0\0b\0c\0"), (UTF_8, false));
+        assert_eq!(detect(&utf16("odd", true)[1..]), (UTF_8, false));
+    }
+
+    #[test]
+    fn utf8_and_windows_1252() {
+        assert_eq!(detect(b""), (UTF_8, false));
+        assert_eq!(detect("caf\u{e9}".as_bytes()), (UTF_8, false));
+        assert_eq!(detect(b"caf\xE9 \x93quoted\x94"), (WINDOWS_1252, false));
+
+        let (text, bom) = decode(b"caf\xE9 \x93quoted\x94", WINDOWS_1252).unwrap();
+        assert_eq!(text, "caf\u{e9} \u{201c}quoted\u{201d}");
+        assert!(!bom);
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        assert!(decode(b"caf\xE9", UTF_8).is_err());
+        assert!(encode("\u{1F600}", WINDOWS_1252, false).is_err());
+        // Encodings that can only be decoded are refused rather than written as UTF-8.
+        assert!(encode("a", REPLACEMENT, false).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "line one\r\ncaf\u{e9} \u{1F600}\n";
+        for (encoding, bom) in [(UTF_8, false), (UTF_8, true), (UTF_16LE, false), (UTF_16LE, true), (UTF_16BE, true)] {
+            let bytes = encode(text, encoding, bom).unwrap();
+            assert_eq!(bytes.starts_with(bom_for(encoding)), bom);
+            assert_eq!(detect(&bytes), (encoding, bom));
+            assert_eq!(decode(&bytes, encoding).unwrap(), (String::from(text), bom));
+        }
+
+        let bytes = b"caf\xE9\r\n\x80\n";
+        let (text, bom) = decode(bytes, WINDOWS_1252).unwrap();
+        assert_eq!(encode(&text, WINDOWS_1252, bom).unwrap(), bytes);
+    }
+}
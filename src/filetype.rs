@@ -0,0 +1,37 @@
+use std::path::Path;
+
+// Guesses a file's type from its name. The names match vim's so that settings carry over.
+pub fn detect(name: &str) -> &'static str {
+    let path = Path::new(name);
+    if let Some(name) = path.file_name().and_then(|v| v.to_str()) {
+        match name {
+            "Makefile" | "makefile" | "GNUmakefile" => return "make",
+            "Dockerfile" => return "dockerfile",
+            ".bashrc" | ".bash_profile" | ".profile" => return "sh",
+            _ => (),
+        }
+    }
+
+    match path.extension().and_then(|v| v.to_str()).unwrap_or("") {
+        "rs" => "rust",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" => "cpp",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "go" => "go",
+        "java" => "java",
+        "sh" | "bash" | "zsh" => "sh",
+        "lisp" | "cl" => "lisp",
+        "el" => "elisp",
+        "scm" | "ss" => "scheme",
+        "clj" | "cljs" | "edn" => "clojure",
+        "css" | "scss" | "less" => "css",
+        "html" | "htm" => "html",
+        "md" | "markdown" => "markdown",
+        "toml" => "toml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        _ => "",
+    }
+}
@@ -0,0 +1,60 @@
+use ropey::{str_utils::byte_to_char_idx, RopeSlice};
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
+use unicode_width::UnicodeWidthChar;
+
+// Grapheme boundaries are found chunk by chunk so that stepping over a cluster stays cheap no
+// matter how large the rope is.
+
+pub fn prev_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
+    let byte_idx = slice.char_to_byte(char_idx);
+    let (mut chunk, mut chunk_byte_idx, mut chunk_char_idx, _) = slice.chunk_at_byte(byte_idx);
+    let mut cursor = GraphemeCursor::new(byte_idx, slice.len_bytes(), true);
+
+    loop {
+        match cursor.prev_boundary(chunk, chunk_byte_idx) {
+            Ok(None) => return 0,
+            Ok(Some(n)) => return chunk_char_idx + byte_to_char_idx(chunk, n - chunk_byte_idx),
+            Err(GraphemeIncomplete::PrevChunk) => {
+                let (a, b, c, _) = slice.chunk_at_byte(chunk_byte_idx - 1);
+                chunk = a;
+                chunk_byte_idx = b;
+                chunk_char_idx = c;
+            }
+            Err(GraphemeIncomplete::PreContext(n)) => {
+                let context = slice.chunk_at_byte(n - 1).0;
+                cursor.provide_context(context, n - context.len());
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub fn next_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
+    let byte_idx = slice.char_to_byte(char_idx);
+    let (mut chunk, mut chunk_byte_idx, mut chunk_char_idx, _) = slice.chunk_at_byte(byte_idx);
+    let mut cursor = GraphemeCursor::new(byte_idx, slice.len_bytes(), true);
+
+    loop {
+        match cursor.next_boundary(chunk, chunk_byte_idx) {
+            Ok(None) => return slice.len_chars(),
+            Ok(Some(n)) => return chunk_char_idx + byte_to_char_idx(chunk, n - chunk_byte_idx),
+            Err(GraphemeIncomplete::NextChunk) => {
+                chunk_byte_idx += chunk.len();
+                let (a, _, c, _) = slice.chunk_at_byte(chunk_byte_idx);
+                chunk = a;
+                chunk_char_idx = c;
+            }
+            Err(GraphemeIncomplete::PreContext(n)) => {
+                let context = slice.chunk_at_byte(n - 1).0;
+                cursor.provide_context(context, n - context.len());
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+// The number of terminal cells a grapheme cluster takes up. This matches how tui lays out text, so
+// wide characters take two cells and combining marks add nothing to the cluster they belong to.
+pub fn width(grapheme: &RopeSlice) -> usize {
+    grapheme.chars().map(|c| c.width().unwrap_or(0)).sum()
+}
@@ -0,0 +1,287 @@
+pub const BYTES_PER_ROW: usize = 16;
+
+// A file that is not text is edited as raw bytes. Edits overwrite bytes in place one hex digit at
+// a time, so the length of the file never changes.
+pub struct HexView {
+    bytes: Vec<u8>,
+    cursor: usize,
+    low_nibble: bool,
+    pub scroll: usize,
+    update_scroll: bool,
+    pub dirty: bool,
+}
+
+pub struct HexRow {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl HexRow {
+    pub fn hex(&self) -> String {
+        let mut hex: Vec<_> = self.bytes.iter().map(|v| format!("{:02x}", v)).collect();
+        hex.resize(BYTES_PER_ROW, String::from("  "));
+        hex.join(" ")
+    }
+
+    pub fn ascii(&self) -> String {
+        self.bytes
+            .iter()
+            .map(|&v| {
+                if v.is_ascii_graphic() || v == b' ' {
+                    v as char
+                } else {
+                    '.'
+                }
+            })
+            .collect()
+    }
+}
+
+impl HexView {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        HexView {
+            bytes,
+            cursor: 0,
+            low_nibble: false,
+            scroll: 0,
+            update_scroll: false,
+            dirty: false,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.bytes.len().div_ceil(BYTES_PER_ROW).max(1)
+    }
+
+    pub fn move_left(&mut self) {
+        self.goto(self.cursor.saturating_sub(1));
+    }
+
+    pub fn move_right(&mut self) {
+        self.goto(self.cursor + 1);
+    }
+
+    pub fn move_up(&mut self) {
+        if self.cursor >= BYTES_PER_ROW {
+            self.goto(self.cursor - BYTES_PER_ROW);
+        }
+    }
+
+    pub fn move_down(&mut self) {
+        if self.cursor / BYTES_PER_ROW + 1 < self.row_count() {
+            self.goto((self.cursor + BYTES_PER_ROW).min(self.bytes.len() - 1));
+        }
+    }
+
+    pub fn goto(&mut self, offset: usize) -> bool {
+        if offset >= self.bytes.len() && offset != 0 {
+            return false;
+        }
+
+        self.cursor = offset;
+        self.low_nibble = false;
+        self.update_scroll = true;
+        true
+    }
+
+    // Overwrites the digit under the cursor, moving on to the next byte after the low digit.
+    pub fn set_nibble(&mut self, c: char) -> bool {
+        let digit = match c.to_digit(16) {
+            Some(v) => v as u8,
+            None => return false,
+        };
+
+        let byte = match self.bytes.get_mut(self.cursor) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        if self.low_nibble {
+            *byte = (*byte & 0xf0) | digit;
+            self.low_nibble = false;
+            if self.cursor + 1 < self.bytes.len() {
+                self.cursor += 1;
+            }
+        } else {
+            *byte = (*byte & 0x0f) | (digit << 4);
+            self.low_nibble = true;
+        }
+        self.dirty = true;
+        self.update_scroll = true;
+        true
+    }
+
+    pub fn prev_nibble(&mut self) {
+        if self.low_nibble {
+            self.low_nibble = false;
+        } else if self.cursor > 0 {
+            self.cursor -= 1;
+            self.low_nibble = true;
+        }
+        self.update_scroll = true;
+    }
+
+    pub fn update_scroll(&mut self, height: usize) {
+        if self.update_scroll {
+            self.update_scroll = false;
+
+            let row = self.cursor / BYTES_PER_ROW;
+            if row >= self.scroll + height {
+                self.scroll = row + 1 - height;
+            } else if row < self.scroll {
+                self.scroll = row;
+            }
+        }
+    }
+
+    // Where the cursor goes in the hex pane of a text area starting at `x` and `y`.
+    pub fn cursor_pos(&self, x: usize, y: usize) -> (usize, usize) {
+        (
+            x + (self.cursor % BYTES_PER_ROW) * 3 + self.low_nibble as usize,
+            y + self.cursor / BYTES_PER_ROW - self.scroll,
+        )
+    }
+
+    pub fn rows(&self, height: usize) -> impl Iterator<Item = HexRow> + '_ {
+        self.bytes
+            .chunks(BYTES_PER_ROW)
+            .enumerate()
+            .skip(self.scroll)
+            .take(height)
+            .map(|(i, v)| HexRow {
+                offset: i * BYTES_PER_ROW,
+                bytes: v.to_vec(),
+            })
+    }
+}
+
+// Text almost never contains NUL bytes or many control characters, so either is taken as a sign
+// that a file is binary.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(8192)];
+    if sample.contains(&0) {
+        return true;
+    }
+
+    let control = sample
+        .iter()
+        .filter(|&&v| (v < 0x20 && !matches!(v, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)) || v == 0x7f)
+        .count();
+    control * 10 > sample.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::buffer::Buffer;
+
+    #[test]
+    fn binary_detection() {
+        assert!(is_binary(b"\x7fELF\x02\x01\x01\0"));
+        assert!(is_binary(b"\x01\x02\x03\x04 text"));
+        assert!(!is_binary(b""));
+        assert!(!is_binary(b"plain text\twith tabs\r\n"));
+        assert!(!is_binary(b"\x1b[31mcolored\x1b[0m\x0c"));
+        assert!(!is_binary("caf\u{e9}".as_bytes()));
+    }
+
+    #[test]
+    fn nibbles() {
+        let mut hex = HexView::new(vec![0x00, 0xff, 0x12]);
+        assert!(hex.set_nibble('a'));
+        assert_eq!(hex.cursor_pos(0, 0), (1, 0));
+        assert!(hex.set_nibble('B'));
+        assert_eq!(hex.bytes(), [0xab, 0xff, 0x12]);
+        assert_eq!(hex.cursor(), 1);
+        assert!(hex.dirty);
+
+        assert!(!hex.set_nibble('g'));
+        assert!(hex.set_nibble('0'));
+        hex.prev_nibble();
+        hex.prev_nibble();
+        assert_eq!((hex.cursor(), hex.cursor_pos(0, 0)), (0, (1, 0)));
+        assert!(hex.set_nibble('c'));
+        assert_eq!(hex.bytes(), [0xac, 0x0f, 0x12]);
+
+        // The last digit of the file leaves the cursor where it is.
+        hex.goto(2);
+        hex.set_nibble('3');
+        hex.set_nibble('4');
+        assert_eq!(hex.cursor(), 2);
+        assert_eq!(hex.bytes(), [0xac, 0x0f, 0x34]);
+    }
+
+    #[test]
+    fn movement() {
+        let mut hex = HexView::new((0..40).collect());
+        hex.move_down();
+        hex.move_down();
+        assert_eq!(hex.cursor(), 32);
+        hex.move_right();
+        hex.move_down();
+        assert_eq!(hex.cursor(), 33);
+        assert!(!hex.goto(40));
+        assert!(hex.goto(39));
+        hex.move_up();
+        assert_eq!(hex.cursor(), 23);
+
+        // Moving resets a half typed byte.
+        hex.set_nibble('f');
+        hex.move_left();
+        assert_eq!(hex.cursor_pos(0, 0), (18, 1));
+    }
+
+    #[test]
+    fn empty_file() {
+        let mut hex = HexView::new(Vec::new());
+        hex.move_right();
+        hex.move_down();
+        hex.move_up();
+        hex.move_left();
+        hex.prev_nibble();
+        assert_eq!(hex.cursor(), 0);
+        assert!(!hex.set_nibble('1'));
+        assert!(!hex.dirty);
+        assert_eq!(hex.row_count(), 1);
+        assert_eq!(hex.rows(10).count(), 0);
+    }
+
+    #[test]
+    fn rows() {
+        let mut hex = HexView::new((0x3e..0x52).collect());
+        hex.goto(17);
+        hex.update_scroll(1);
+        let rows: Vec<_> = hex.rows(10).collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].offset, 16);
+        assert_eq!(rows[0].ascii(), "NOPQ");
+        assert!(rows[0].hex().starts_with("4e 4f 50 51    "));
+        assert_eq!(rows[0].hex().len(), BYTES_PER_ROW * 3 - 1);
+    }
+
+    #[test]
+    fn writes_the_edited_bytes() {
+        let path = env::temp_dir().join(format!("nu-hex-test-{}", std::process::id()));
+        fs::write(&path, b"\x7fELF\x02\x01\x01\0\0\0").unwrap();
+        let mut buffer = Buffer::open(path.to_str().unwrap()).unwrap();
+        assert!(buffer.hex().is_some());
+
+        buffer.apply_motion(crate::motion::Motion::Right, None);
+        buffer.char('f');
+        buffer.char('e');
+        buffer.write().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(bytes, b"\x7f\xfeLF\x02\x01\x01\0\0\0");
+    }
+}
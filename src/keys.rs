@@ -0,0 +1,29 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+// Macros are kept in registers as text, with each key as the character a terminal would send for
+// it like vim does: Ctrl combinations are control characters and Esc, Enter, Backspace and Tab are
+// their ASCII codes. Keys with no such character, like the arrows, are not recorded.
+pub fn to_char(key: &KeyEvent) -> Option<char> {
+    Some(match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) && c.is_ascii_alphabetic() => {
+            (c.to_ascii_lowercase() as u8 & 0x1f) as char
+        }
+        KeyCode::Char(c) => c,
+        KeyCode::Esc => '\x1b',
+        KeyCode::Enter => '\r',
+        KeyCode::Backspace => '\x7f',
+        KeyCode::Tab => '\t',
+        _ => return None,
+    })
+}
+
+pub fn from_char(c: char) -> KeyEvent {
+    match c {
+        '\x1b' => KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE),
+        '\r' | '\n' => KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE),
+        '\x7f' | '\x08' => KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE),
+        '\t' => KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE),
+        '\x01'..='\x1a' => KeyEvent::new(KeyCode::Char((c as u8 + b'a' - 1) as char), KeyModifiers::CONTROL),
+        c => KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+    }
+}
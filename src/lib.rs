@@ -1,2 +1,15 @@
 pub mod buffer;
-
+pub mod clipboard;
+pub mod encoding;
+pub mod filetype;
+pub mod grapheme;
+pub mod hex;
+pub mod keys;
+pub mod motion;
+pub mod normal;
+pub mod operator;
+pub mod registers;
+pub mod search;
+pub mod substitute;
+pub mod textobject;
+pub mod undo;
@@ -1,19 +1,32 @@
 use std::{
-    fs,
     io::{self, Error},
-    time::Duration, env,
+    time::{Duration, SystemTime}, env,
 };
 
 use crossterm::{
     cursor::{CursorShape, SetCursorShape},
-    event::{DisableMouseCapture, EnableMouseCapture, KeyCode},
+    event::{DisableMouseCapture, EnableMouseCapture, KeyCode, KeyEvent, KeyModifiers},
     execute,
     terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use nu::buffer::{Buffer, Buffers};
+use nu::{
+    buffer::{Buffer, Buffers, ExtmarkStyle, Highlight, LineEnding, Position, SelectionKind},
+    encoding,
+    clipboard,
+    registers::Registers,
+    search,
+    motion::{Motion, WordChars},
+    keys,
+    normal::{self, Action, Change, Command, InsertKey, Parse},
+    operator::Operator,
+    textobject::TextObject,
+    substitute::{self, Substitution},
+};
+use regex::Regex;
 use tui::{
     backend::CrosstermBackend,
     layout,
+    style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets, Terminal,
 };
@@ -22,279 +35,1279 @@ enum Mode {
     Normal,
     Command,
     Insert,
+    Visual(SelectionKind),
+    Search { forward: bool },
+    Substitute,
+    UndoTree { selected: usize },
+    Registers,
 }
 
-fn main() -> Result<(), Error> {
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut stdout = io::stdout();
-    let mut terminal = Terminal::new(backend)?;
-    crossterm::terminal::enable_raw_mode()?;
-    terminal.clear()?;
+enum TimeTravel {
+    Steps(usize),
+    Time(Duration),
+}
 
-    let mut running = true;
-    let mut mode = Mode::Normal;
-    let mut editor_command = String::new();
-    let mut message = None;
+fn parse_time_travel(arg: Option<&&str>) -> Option<TimeTravel> {
+    let arg = match arg {
+        Some(v) => *v,
+        None => return Some(TimeTravel::Steps(1)),
+    };
 
-    let args: Vec<_> = env::args().collect();
-    let buffer = if args.len() > 1 {
-        let name = &args[1];
-        match fs::read_to_string(name) {
-            Ok(v) => Buffer::new(name, true, &v),
-            Err(e) => {
-                message = Some(format!("Could not open file `{}`: {}", name, e));
-                Buffer::new("[buffer]", false, "")
+    if let Ok(count) = arg.parse() {
+        return Some(TimeTravel::Steps(count));
+    }
+
+    let (i, unit) = arg.char_indices().last()?;
+    let count: u64 = arg[..i].parse().ok()?;
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        _ => return None,
+    };
+    Some(TimeTravel::Time(Duration::from_secs(count.checked_mul(unit)?)))
+}
+
+// Handles a single `:set` argument: `name=value` sets an option, `name?` (or just `name` for
+// options that are not on/off switches) shows it, and `name`/`noname` turn a switch on or off.
+fn set_option(buffer: &mut Buffer, registers: &mut Registers, option: &str) -> Result<Option<String>, String> {
+    let (name, value) = match option.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (option.trim_end_matches('?'), None),
+    };
+    let query = option.ends_with('?');
+
+    match (name, value) {
+        ("fileformat" | "ff", Some(value)) => match LineEnding::from_name(value) {
+            Some(line_ending) => {
+                buffer.set_line_ending(line_ending);
+                Ok(None)
+            }
+
+            None => Err(format!("Invalid fileformat `{}`", value)),
+        },
+
+        ("fileformat" | "ff", None) => Ok(Some(format!(
+            "fileformat={}",
+            buffer.line_ending().name()
+        ))),
+
+        ("endofline" | "eol", None) if query => Ok(Some(String::from(if buffer.final_newline() {
+            "endofline"
+        } else {
+            "noendofline"
+        }))),
+
+        ("endofline" | "eol", None) => {
+            buffer.set_final_newline(true);
+            Ok(None)
+        }
+
+        ("noendofline" | "noeol", None) => {
+            buffer.set_final_newline(false);
+            Ok(None)
+        }
+
+        ("fileencoding" | "fenc", Some(value)) => match encoding::lookup(value) {
+            Some(encoding) => {
+                buffer.set_encoding(encoding);
+                Ok(None)
             }
+
+            None => Err(format!("Unknown encoding `{}`", value)),
+        },
+
+        ("fileencoding" | "fenc", None) => Ok(Some(format!(
+            "fileencoding={}",
+            buffer.encoding().name().to_lowercase()
+        ))),
+
+        ("bomb", None) if query => Ok(Some(String::from(if buffer.bom() {
+            "bomb"
+        } else {
+            "nobomb"
+        }))),
+
+        ("bomb", None) => {
+            buffer.set_bom(true);
+            Ok(None)
+        }
+
+        ("nobomb", None) => {
+            buffer.set_bom(false);
+            Ok(None)
+        }
+
+        ("filetype" | "ft", Some(value)) => {
+            buffer.set_filetype(value);
+            Ok(None)
+        }
+
+        ("filetype" | "ft", None) => Ok(Some(format!("filetype={}", buffer.filetype()))),
+
+        ("wrap", None) if query => Ok(Some(String::from(if buffer.wrap() {
+            "wrap"
+        } else {
+            "nowrap"
+        }))),
+
+        ("wrap", None) => {
+            buffer.set_wrap(true);
+            Ok(None)
         }
+
+        ("nowrap", None) => {
+            buffer.set_wrap(false);
+            Ok(None)
+        }
+
+        ("iskeyword" | "isk", Some(value)) => {
+            buffer.set_word_chars(WordChars::parse(value));
+            Ok(None)
+        }
+
+        ("iskeyword" | "isk", None) => Ok(Some(format!("iskeyword={}", buffer.word_chars().extra()))),
+
+        ("clipboard" | "cb", Some(value)) => match clipboard::from_name(value) {
+            Some(provider) => {
+                registers.set_clipboard(provider);
+                Ok(None)
+            }
+
+            None => Err(format!("Clipboard provider `{}` is not available", value)),
+        },
+
+        ("clipboard" | "cb", None) => Ok(Some(format!("clipboard={}", registers.clipboard().name()))),
+
+        _ => Err(format!("Unknown option `{}`", option)),
+    }
+}
+
+// Moves to the next match of a search and returns the message to show, if any.
+// Moves to the next match and returns the message to show, or an error if nothing matched.
+fn search_next(buffer: &mut Buffer, regex: &Regex, from: usize, forward: bool) -> Result<Option<String>, String> {
+    match buffer.search(regex, from, forward) {
+        Some(true) if forward => Ok(Some(String::from("search hit BOTTOM, continuing at TOP"))),
+        Some(true) => Ok(Some(String::from("search hit TOP, continuing at BOTTOM"))),
+        Some(false) => Ok(None),
+        None => Err(format!("Pattern not found: {}", regex.as_str())),
+    }
+}
+
+fn format_age(time: SystemTime) -> String {
+    let seconds = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    if seconds < 60 {
+        format!("{}s ago", seconds)
+    } else if seconds < 60 * 60 {
+        format!("{}m ago", seconds / 60)
+    } else if seconds < 60 * 60 * 24 {
+        format!("{}h ago", seconds / 60 / 60)
     } else {
-        Buffer::new("[buffer]", false, "")
-    };
-    let mut buffers = Buffers::new(buffer);
+        format!("{}d ago", seconds / 60 / 60 / 24)
+    }
+}
 
-    while running {
-        if let Ok(true) = crossterm::event::poll(Duration::from_millis(10)) {
-            if let Ok(event) = crossterm::event::read() {
-                match event {
-                    crossterm::event::Event::Key(key) => match mode {
-                        Mode::Normal => match key.code {
-                            KeyCode::Backspace => (),
-                            KeyCode::Enter => (),
-                            KeyCode::Left => (),
-                            KeyCode::Right => (),
-                            KeyCode::Up => (),
-                            KeyCode::Down => (),
-                            KeyCode::Home => (),
-                            KeyCode::End => (),
-                            KeyCode::PageUp => (),
-                            KeyCode::PageDown => (),
-                            KeyCode::Tab => (),
-                            KeyCode::BackTab => (),
-                            KeyCode::Delete => (),
-                            KeyCode::Insert => (),
-                            KeyCode::F(_) => (),
+const MAX_MACRO_DEPTH: usize = 100;
+// The most text a counted put can add at once, in bytes.
+const MAX_PUT_LEN: usize = 64 * 1024 * 1024;
 
-                            KeyCode::Char(':') => {
-                                mode = Mode::Command;
-                                editor_command.clear();
+// Everything the editor keeps track of between keys.
+struct Editor {
+    running: bool,
+    mode: Mode,
+    editor_command: String,
+    message: Option<String>,
+    registers: Registers,
+    register: Option<char>,
+    awaiting_register: bool,
+    parser: normal::Parser,
+    object_pending: Option<bool>,
+    last_change: Option<Change>,
+    inserting: Option<Change>,
+    last_search: Option<(Regex, bool)>,
+    highlight_search: bool,
+    search_origin: (usize, usize, usize),
+    substitution: Option<Substitution>,
+    // The register a macro is being recorded into and the keys typed so far.
+    recording: Option<(char, String)>,
+    last_macro: Option<char>,
+    macro_depth: usize,
+    // Set when a motion fails, which stops a macro that is playing.
+    failed: bool,
+    buffers: Buffers,
+}
+
+impl Editor {
+    fn new(buffers: Buffers) -> Self {
+        Editor {
+            running: true,
+            mode: Mode::Normal,
+            editor_command: String::new(),
+            message: None,
+            registers: Registers::new(clipboard::detect()),
+            register: None,
+            awaiting_register: false,
+            parser: normal::Parser::new(),
+            object_pending: None,
+            last_change: None,
+            inserting: None,
+            last_search: None,
+            highlight_search: false,
+            search_origin: (0, 0, 0),
+            substitution: None,
+            recording: None,
+            last_macro: None,
+            macro_depth: 0,
+            failed: false,
+            buffers,
+        }
+    }
+
+    // Feeds the keys of a macro through `handle_key` as if they were typed, `count` times over.
+    // Playing stops at the first motion that fails, and a macro that plays itself stops after
+    // `MAX_MACRO_DEPTH` levels.
+    fn play(&mut self, text: &str, count: usize) {
+        if self.macro_depth == 0 {
+            self.failed = false;
+        } else if self.macro_depth >= MAX_MACRO_DEPTH {
+            self.failed = true;
+            return;
+        }
+
+        self.macro_depth += 1;
+        'play: for _ in 0..count {
+            for c in text.chars() {
+                self.handle_key(keys::from_char(c));
+                if self.failed {
+                    break 'play;
+                }
+            }
+        }
+        self.macro_depth -= 1;
+    }
+
+    // Moves the cursor in the current buffer, adding where it was to the jump list if the motion
+    // is a jump.
+    fn move_cursor(&mut self, motion: Motion, count: Option<usize>) {
+        let buffer = self.buffers.get_current_mut();
+        let cursor = buffer.cursor_index();
+        let moved = buffer.apply_motion(motion, count);
+        if moved && motion.is_jump() {
+            self.buffers.push_jump(cursor);
+        }
+        self.failed |= !moved;
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        if self.macro_depth == 0 {
+            if let (Some((_, recorded)), Some(c)) = (self.recording.as_mut(), keys::to_char(&key)) {
+                recorded.push(c);
+            }
+        }
+
+        // The key after `"` names the register the next command uses.
+        if self.awaiting_register {
+            self.awaiting_register = false;
+            match key.code {
+                KeyCode::Char(c) if Registers::is_valid(c) => self.register = Some(c),
+                _ => self.register = None,
+            }
+            return;
+        }
+
+        // A count typed in visual mode only goes with motions, and is dropped by the keys that act
+        // on the selection.
+        if let (Mode::Visual(_), KeyCode::Char(c)) = (&self.mode, key.code) {
+            let counting = self.parser.pending().chars().all(|c| c.is_ascii_digit());
+            if counting && ("iaovV\":".contains(c) || Operator::from_visual_key(c).is_some()) {
+                self.parser.reset();
+            }
+        }
+
+        match self.mode {
+            Mode::Normal => {
+                let command = match key.code {
+                    KeyCode::Esc => {
+                        self.parser.reset();
+                        None
+                    }
+
+                    // `q` on its own stops a recording rather than starting one.
+                    KeyCode::Char('q') if self.recording.is_some() && self.parser.is_empty() => {
+                        let (name, mut recorded) = self.recording.take().unwrap();
+                        recorded.pop();
+                        if let Err(e) = self.registers.record(name, recorded) {
+                            self.message = Some(format!("Could not copy to clipboard: {}", e));
+                        }
+                        None
+                    }
+
+                    // Ctrl combinations reach the parser as the control characters they type, so
+                    // Tab is `Ctrl-I`.
+                    _ => match keys::to_char(&key).map(|c| self.parser.push(c)) {
+                        Some(Parse::Done(v)) => Some(v),
+                        _ => None,
+                    },
+                };
+
+                // `.` runs the last change again, with the count it is given in place of
+                // the original one.
+                let mut replay = None;
+                let command = match command {
+                    Some(Command::Action {
+                        count,
+                        action: Action::Repeat,
+                        ..
+                    }) => self.last_change.clone().map(|change| {
+                        replay = Some(change.inserted);
+                        if count.is_some() {
+                            change.command.with_count(count)
+                        } else {
+                            change.command
+                        }
+                    }),
+                    command => command,
+                };
+
+                let hex = self.buffers.get_current().hex().is_some();
+                match command {
+                    None => (),
+
+                    // An uppercase mark can be in another buffer, which is switched to first.
+                    Some(Command::Move {
+                        motion: motion @ Motion::Mark { name, .. },
+                        ..
+                    }) if name.is_ascii_uppercase() && self.buffers.get_current().mark(name).is_none() => {
+                        match self.buffers.goto_global_mark(name) {
+                            Ok(true) => {
+                                self.buffers.get_current_mut().apply_motion(motion, None);
+                            }
+                            Ok(false) => {
+                                self.message = Some(String::from("Mark not set"));
+                                self.failed = true;
                             }
+                            Err(e) => {
+                                self.message = Some(format!("Could not open file: {}", e));
+                                self.failed = true;
+                            }
+                        }
+                    }
+
+                    Some(Command::Move { count, motion }) => self.move_cursor(motion, count),
+
+                    Some(Command::Operate { .. }) if hex => (),
 
-                            KeyCode::Char('i') => {
-                                mode = Mode::Insert;
+                    Some(Command::Operate {
+                        count,
+                        register,
+                        operator,
+                        target,
+                    }) => {
+                        let buffer = self.buffers.get_current_mut();
+                        if let Some(selection) = normal::selection(buffer, operator, target, count) {
+                            match operator.apply(buffer, &selection, &mut self.registers, register) {
+                                Ok(v) => self.message = v,
+                                Err(e) => self.message = Some(format!("Could not copy to clipboard: {}", e)),
                             }
 
-                            KeyCode::Char('h') => {
-                                buffers.get_current_mut().move_left();
+                            if operator.inserts() {
+                                self.mode = Mode::Insert;
+                            } else {
+                                buffer.commit();
                             }
+                        } else {
+                            self.failed = true;
+                        }
+                    }
 
-                            KeyCode::Char('j') => {
-                                buffers.get_current_mut().move_down();
+                    Some(Command::Action { count, register, action }) => match action {
+                        Action::Insert => self.mode = Mode::Insert,
+
+                        Action::Command => {
+                            self.mode = Mode::Command;
+                            self.editor_command.clear();
+                        }
+
+                        Action::Visual(_) | Action::Search { .. } if hex => (),
+
+                        Action::Visual(kind) => {
+                            self.buffers.get_current_mut().start_selection(kind);
+                            self.mode = Mode::Visual(kind);
+                        }
+
+                        Action::Search { forward } => {
+                            let buffer = self.buffers.get_current();
+                            self.search_origin = (buffer.cursor_index(), buffer.vscroll, buffer.hscroll);
+                            self.editor_command.clear();
+                            self.mode = Mode::Search { forward };
+                        }
+
+                        Action::SearchNext { reverse } => match self.last_search.as_ref() {
+                            Some((regex, forward)) => {
+                                let forward = *forward != reverse;
+                                let buffer = self.buffers.get_current_mut();
+                                let cursor = buffer.cursor_index();
+                                self.highlight_search = true;
+                                for _ in 0..count.unwrap_or(1) {
+                                    match search_next(buffer, regex, buffer.cursor_index(), forward) {
+                                        Ok(message) => self.message = message,
+                                        Err(e) => {
+                                            self.message = Some(e);
+                                            self.failed = true;
+                                            break;
+                                        }
+                                    }
+                                }
+                                if buffer.cursor_index() != cursor {
+                                    self.buffers.push_jump(cursor);
+                                }
                             }
 
-                            KeyCode::Char('k') => {
-                                buffers.get_current_mut().move_up();
+                            None => {
+                                self.message = Some(String::from("No previous search pattern"));
+                                self.failed = true;
                             }
+                        },
 
-                            KeyCode::Char('l') => {
-                                buffers.get_current_mut().move_right();
+                        Action::Put { after } => {
+                            let name = register.unwrap_or('"');
+                            match self.registers.get(name) {
+                                Ok(Some(v)) => {
+                                    let count = match v.kind {
+                                        SelectionKind::Block => 1,
+                                        _ => count.unwrap_or(1),
+                                    };
+                                    match v.text.len().checked_mul(count) {
+                                        Some(len) if len <= MAX_PUT_LEN => {
+                                            let buffer = self.buffers.get_current_mut();
+                                            buffer.put(&v.text.repeat(count), v.kind, after);
+                                            buffer.commit();
+                                        }
+                                        _ => {
+                                            self.message = Some(String::from("Too much text to put"));
+                                            self.failed = true;
+                                        }
+                                    }
+                                }
+                                Ok(None) => self.message = Some(format!("Nothing in register {}", name)),
+                                Err(e) => self.message = Some(format!("Could not paste from clipboard: {}", e)),
                             }
+                        }
 
-                            KeyCode::Char('[') => {
-                                buffers.prev();
+                        Action::Undo => {
+                            for _ in 0..count.unwrap_or(1) {
+                                if !self.buffers.get_current_mut().undo() {
+                                    self.message = Some(String::from("Already at oldest change"));
+                                    break;
+                                }
                             }
+                        }
 
-                            KeyCode::Char(']') => {
-                                buffers.next();
+                        Action::Redo => {
+                            for _ in 0..count.unwrap_or(1) {
+                                if !self.buffers.get_current_mut().redo() {
+                                    self.message = Some(String::from("Already at newest change"));
+                                    break;
+                                }
                             }
+                        }
 
-                            KeyCode::Char(_) => (),
-                            KeyCode::Null => (),
-                            KeyCode::Esc => (),
-                        },
+                        Action::Repeat => (),
 
-                        Mode::Command => match key.code {
-                            KeyCode::Backspace => {
-                                if editor_command.pop().is_none() {
-                                    mode = Mode::Normal;
+                        Action::Record(name) => {
+                            if matches!(name, '"' | 'a'..='z' | 'A'..='Z' | '0'..='9' | '+' | '*') {
+                                self.recording = Some((name, String::new()));
+                            } else {
+                                self.message = Some(format!("Invalid register {}", name));
+                            }
+                        }
+
+                        Action::Play(name) => {
+                            let name = match (name, self.last_macro) {
+                                ('@', Some(v)) => v,
+                                ('@', None) => {
+                                    self.message = Some(String::from("No previous macro"));
+                                    return;
                                 }
+                                (v, _) => v,
+                            };
+
+                            match self.registers.get(name) {
+                                Ok(Some(v)) => {
+                                    self.last_macro = Some(name);
+                                    self.play(&v.text, count.unwrap_or(1));
+                                }
+                                Ok(None) => self.message = Some(format!("Nothing in register {}", name)),
+                                Err(e) => self.message = Some(format!("Could not paste from clipboard: {}", e)),
+                            }
+                        }
+                        Action::SetMark(name) => {
+                            if name.is_ascii_alphabetic() {
+                                self.buffers.set_mark(name);
+                            } else {
+                                self.message = Some(format!("Invalid mark {}", name));
                             }
+                        }
 
-                            KeyCode::Enter => {
-                                mode = Mode::Normal;
-                                message = None;
-                                let args: Vec<_> = editor_command.split_whitespace().collect();
-                                match args.first().cloned() {
-                                    Some("quit" | "q") => {
-                                        if let Some(buffer) = buffers.modified() {
-                                            message = Some(format!(
-                                                "Cannot quit: unsaved buffer `{}`",
-                                                buffer.name
-                                            ));
-                                        } else {
-                                            running = false;
-                                        }
-                                    }
+                        Action::JumpBack => {
+                            self.failed |= !self.buffers.jump_back(count.unwrap_or(1));
+                        }
 
-                                    Some("quit!" | "q!") => {
-                                        running = false;
-                                    }
+                        Action::JumpForward => {
+                            self.failed |= !self.buffers.jump_forward(count.unwrap_or(1));
+                        }
 
-                                    Some("close" | "c") => {
-                                        if buffers.get_current().modified {
-                                            message = Some(format!("Cannot close unsaved buffer `{}`", buffers.get_current().name));
-                                        } else {
-                                            buffers.remove_current();
-                                        }
+                        Action::PrevBuffer => self.buffers.prev(),
+                        Action::NextBuffer => self.buffers.next(),
+                    },
+                }
+
+                if let Some(command) = command.filter(Command::changes) {
+                    match replay {
+                        Some(inserted) => {
+                            // A repeated insert types its text as many times as the count.
+                            if let Mode::Insert = self.mode {
+                                let times = match command {
+                                    Command::Action {
+                                        count,
+                                        action: Action::Insert,
+                                        ..
+                                    } => count.unwrap_or(1),
+                                    _ => 1,
+                                };
+                                let buffer = self.buffers.get_current_mut();
+                                for _ in 0..times {
+                                    for key in &inserted {
+                                        key.apply(buffer);
                                     }
+                                }
+                                buffer.commit();
+                                self.mode = Mode::Normal;
+                            }
+                            self.last_change = Some(Change { command, inserted });
+                        }
+
+                        None => {
+                            let change = Change {
+                                command,
+                                inserted: Vec::new(),
+                            };
+                            if let Mode::Insert = self.mode {
+                                self.inserting = Some(change);
+                            } else {
+                                self.last_change = Some(change);
+                            }
+                        }
+                    }
+                }
+            }
 
-                                    Some("close!" | "c!") => {
-                                        buffers.remove_current();
+            Mode::Command => match key.code {
+                KeyCode::Backspace => {
+                    if self.editor_command.pop().is_none() {
+                        self.mode = Mode::Normal;
+                    }
+                }
+
+                KeyCode::Enter => {
+                    self.mode = Mode::Normal;
+                    self.message = None;
+                    let args: Vec<_> = self.editor_command.split_whitespace().collect();
+                    match args.first().cloned() {
+                        _ if substitute::is_substitute(&self.editor_command) => {
+                            let buffer = self.buffers.get_current_mut();
+                            let command = substitute::parse(&self.editor_command).and_then(|v| {
+                                let regex = v.regex(self.last_search.as_ref().map(|(regex, _)| regex))?;
+                                let lines = v.range.resolve(buffer)?;
+                                Ok((v, regex, lines))
+                            });
+
+                            match command {
+                                Ok((command, regex, lines)) => {
+                                    let mut pending = Substitution::new(buffer, &regex, lines, &command);
+                                    if pending.is_empty() {
+                                        self.message = Some(format!("Pattern not found: {}", regex.as_str()));
+                                    } else if command.confirm {
+                                        buffer.set_cursor_index(pending.current().unwrap().0.start);
+                                        self.substitution = Some(pending);
+                                        self.mode = Mode::Substitute;
+                                    } else {
+                                        pending.replace_all(buffer);
+                                        self.message = Some(pending.finish(buffer));
                                     }
+                                    let forward = self.last_search.as_ref().map(|(_, v)| *v).unwrap_or(true);
+                                    self.last_search = Some((regex, forward));
+                                }
 
-                                    Some("new" | "n") => {
-                                        if args.len() > 2 {
-                                            message = Some(String::from(
-                                                "`new` takes in at most 1 argument",
-                                            ))
-                                        } else {
-                                            let mut buffer = Buffer::new("[buffer]", false, "");
-                                            if args.len() == 2 {
-                                                buffer.name = String::from(args[1]);
-                                                buffer.is_file = true;
-                                            }
+                                Err(e) => self.message = Some(e),
+                            }
+                        }
 
-                                            let id = buffers.add_buffer(buffer);
-                                            buffers.switch(id);
-                                        }
+                        Some("quit" | "q") => {
+                            if let Some(buffer) = self.buffers.modified() {
+                                self.message = Some(format!(
+                                    "Cannot quit: unsaved buffer `{}`",
+                                    buffer.name
+                                ));
+                            } else {
+                                self.running = false;
+                            }
+                        }
+
+                        Some("quit!" | "q!") => {
+                            self.running = false;
+                        }
+
+                        Some("close" | "c") => {
+                            if self.buffers.get_current().modified {
+                                self.message = Some(format!("Cannot close unsaved buffer `{}`", self.buffers.get_current().name));
+                            } else {
+                                let _ = self.buffers.get_current_mut().save_undo_history();
+                                self.buffers.remove_current();
+                            }
+                        }
+
+                        Some("close!" | "c!") => {
+                            let _ = self.buffers.get_current_mut().save_undo_history();
+                            self.buffers.remove_current();
+                        }
+
+                        Some("new" | "n") => {
+                            if args.len() > 2 {
+                                self.message = Some(String::from(
+                                    "`new` takes in at most 1 argument",
+                                ))
+                            } else {
+                                let mut buffer = Buffer::new("[buffer]", false, "");
+                                if args.len() == 2 {
+                                    buffer.name = String::from(args[1]);
+                                    buffer.is_file = true;
+                                }
+
+                                let id = self.buffers.add_buffer(buffer);
+                                self.buffers.switch(id);
+                            }
+                        }
+
+                        Some("open" | "o") => {
+                            if args.len() != 2 {
+                                self.message = Some(String::from("`open` takes in exactly 2 arguments"));
+                            } else {
+                                match Buffer::open(args[1]) {
+                                    Ok(buffer) => {
+                                        let id = self.buffers.add_buffer(buffer);
+                                        self.buffers.switch(id);
                                     }
 
-                                    Some("open" | "o") => {
-                                        if args.len() != 2 {
-                                            message = Some(String::from("`open` takes in exactly 2 arguments"));
-                                        } else {
-                                            match fs::read_to_string(args[1]) {
-                                                Ok(v) => {
-                                                    let buffer = Buffer::new(args[1], true, &v);
-                                                    let id = buffers.add_buffer(buffer);
-                                                    buffers.switch(id);
-                                                }
+                                    Err(e) => {
+                                        self.message = Some(format!(
+                                            "Could not open file `{}`: {}",
+                                            args[1], e
+                                        ))
+                                    }
+                                }
+                            }
+                        }
+
+                        Some("read" | "r") => {
+                            if args.len() != 2 {
+                                self.message = Some(String::from("`read` takes in exactly 1 argument"));
+                            } else {
+                                match Buffer::open(args[1]) {
+                                    Ok(file) if file.hex().is_some() => {
+                                        self.message = Some(format!("Cannot read binary file `{}`", args[1]));
+                                    }
 
-                                                Err(e) => {
-                                                    message = Some(format!(
-                                                        "Could not open file `{}`: {}",
-                                                        args[1], e
-                                                    ))
+                                    Ok(file) => {
+                                        // The file goes in below the cursor's line.
+                                        let buffer = self.buffers.get_current_mut();
+                                        let line = buffer.cursor().0 + 1;
+                                        let mut text = file.text().to_string();
+                                        if !text.is_empty() {
+                                            if line < buffer.line_count() {
+                                                if !text.ends_with('\n') {
+                                                    text.push('\n');
                                                 }
+                                            } else {
+                                                text = format!("\n{}", text.strip_suffix('\n').unwrap_or(&text));
                                             }
+                                            buffer.insert_str_at(Position { line, col: 0 }, &text);
+                                            buffer.commit();
+                                            buffer.set_cursor(line, 0);
                                         }
                                     }
 
-                                    Some("write" | "w") => {
-                                        let buffer = buffers.get_current_mut();
-                                        if args.len() > 2 {
-                                            message = Some(String::from(
-                                                "`write` takes in at most 1 argument",
+                                    Err(e) => {
+                                        self.message = Some(format!(
+                                            "Could not read file `{}`: {}",
+                                            args[1], e
+                                        ))
+                                    }
+                                }
+                            }
+                        }
+
+                        Some("write" | "w") => {
+                            let buffer = self.buffers.get_current_mut();
+                            if args.len() > 2 {
+                                self.message = Some(String::from(
+                                    "`write` takes in at most 1 argument",
+                                ))
+                            } else {
+                                if args.len() == 2 {
+                                    buffer.name = String::from(args[1]);
+                                    buffer.is_file = true;
+                                }
+
+                                if buffer.is_file {
+                                    match buffer.write() {
+                                        Ok(_) => {
+                                            self.message = Some(format!(
+                                                "Saved file `{}`",
+                                                buffer.name
+                                            ));
+                                        }
+
+                                        Err(e) => {
+                                            self.message = Some(format!(
+                                                "Could not save file `{}`: {}",
+                                                buffer.name, e
                                             ))
+                                        }
+                                    }
+                                } else {
+                                    self.message = Some(format!(
+                                        "Cannot save nonfile buffer `{}`",
+                                        buffer.name
+                                    ));
+                                }
+                            }
+                        }
+
+                        Some(command @ ("earlier" | "ea" | "later" | "lat")) => {
+                            let earlier = matches!(command, "earlier" | "ea");
+                            if args.len() > 2 {
+                                self.message = Some(format!(
+                                    "`{}` takes in at most 1 argument",
+                                    command
+                                ))
+                            } else {
+                                let buffer = self.buffers.get_current_mut();
+                                let moved = match parse_time_travel(args.get(1)) {
+                                    Some(TimeTravel::Steps(count)) if earlier => buffer.earlier(count),
+                                    Some(TimeTravel::Steps(count)) => buffer.later(count),
+                                    Some(TimeTravel::Time(duration)) if earlier => buffer.earlier_by(duration),
+                                    Some(TimeTravel::Time(duration)) => buffer.later_by(duration),
+                                    None => {
+                                        self.message = Some(format!("Invalid count `{}`", args[1]));
+                                        true
+                                    }
+                                };
+
+                                if !moved {
+                                    self.message = Some(String::from(if earlier {
+                                        "Already at oldest change"
+                                    } else {
+                                        "Already at newest change"
+                                    }));
+                                }
+                            }
+                        }
+
+                        Some("undo" | "u") => {
+                            if args.len() > 2 {
+                                self.message = Some(String::from(
+                                    "`undo` takes in at most 1 argument",
+                                ))
+                            } else if args.len() == 1 {
+                                if !self.buffers.get_current_mut().undo() {
+                                    self.message = Some(String::from("Already at oldest change"));
+                                }
+                            } else {
+                                match args[1].parse() {
+                                    Ok(seq) => {
+                                        if seq > self.buffers.get_current().undo_tree().last() {
+                                            self.message = Some(format!("Undo number {} not found", seq));
                                         } else {
-                                            if args.len() == 2 {
-                                                buffer.name = String::from(args[1]);
-                                                buffer.is_file = true;
-                                            }
+                                            self.buffers.get_current_mut().goto_undo_state(seq);
+                                        }
+                                    }
 
-                                            if buffer.is_file {
-                                                match fs::write(&buffer.name, buffer.to_string()) {
-                                                    Ok(_) => {
-                                                        message = Some(format!(
-                                                            "Saved file `{}`",
-                                                            buffer.name
-                                                        ));
-                                                        buffer.modified = false;
-                                                    }
-
-                                                    Err(e) => {
-                                                        message = Some(format!(
-                                                            "Could not save file `{}`: {}",
-                                                            buffer.name, e
-                                                        ))
-                                                    }
-                                                }
-                                            } else {
-                                                message = Some(format!(
-                                                    "Cannot save nonfile buffer `{}`",
-                                                    buffer.name
-                                                ));
+                                    Err(_) => {
+                                        self.message = Some(format!("Invalid undo number `{}`", args[1]));
+                                    }
+                                }
+                            }
+                        }
+
+                        Some("redo" | "red") => {
+                            let moved = self.buffers.get_current_mut().redo();
+                            if !moved {
+                                self.message = Some(String::from("Already at newest change"));
+                            }
+                        }
+
+                        Some("nohlsearch" | "noh") => {
+                            self.highlight_search = false;
+                        }
+
+                        Some("registers" | "reg" | "display" | "di") => {
+                            self.mode = Mode::Registers;
+                        }
+
+                        Some("undotree") => {
+                            let selected = self.buffers
+                                .get_current()
+                                .undo_tree()
+                                .entries()
+                                .iter()
+                                .position(|v| v.current)
+                                .unwrap_or(0);
+                            self.mode = Mode::UndoTree { selected };
+                        }
+
+                        Some(command @ ("reopen" | "reopen!")) => {
+                            let buffer = self.buffers.get_current_mut();
+                            if args.len() != 2 {
+                                self.message = Some(String::from(
+                                    "`reopen` takes in exactly 1 argument",
+                                ));
+                            } else if !buffer.is_file {
+                                self.message = Some(format!(
+                                    "Cannot reopen nonfile buffer `{}`",
+                                    buffer.name
+                                ));
+                            } else if command == "reopen" && buffer.modified {
+                                self.message = Some(format!(
+                                    "Cannot reopen unsaved buffer `{}`",
+                                    buffer.name
+                                ));
+                            } else {
+                                match encoding::lookup(args[1]) {
+                                    Some(encoding) => {
+                                        match Buffer::open_with_encoding(&buffer.name, encoding) {
+                                            Ok(v) => self.buffers.replace_current(v),
+                                            Err(e) => {
+                                                self.message = Some(format!(
+                                                    "Could not reopen file `{}`: {}",
+                                                    buffer.name, e
+                                                ))
                                             }
                                         }
                                     }
 
-                                    Some(v) => {
-                                        message = Some(format!("`{}` is not a valid command", v));
+                                    None => {
+                                        self.message = Some(format!("Unknown encoding `{}`", args[1]));
                                     }
-
-                                    None => (),
                                 }
                             }
+                        }
 
-                            KeyCode::Left => (),
-                            KeyCode::Right => (),
-                            KeyCode::Up => (),
-                            KeyCode::Down => (),
-                            KeyCode::Home => (),
-                            KeyCode::End => (),
-                            KeyCode::PageUp => (),
-                            KeyCode::PageDown => (),
-                            KeyCode::Tab => (),
-                            KeyCode::BackTab => (),
-                            KeyCode::Delete => (),
-                            KeyCode::Insert => (),
-                            KeyCode::F(_) => (),
+                        Some("goto" | "go") => {
+                            if args.len() != 2 {
+                                self.message = Some(String::from(
+                                    "`goto` takes in exactly 1 argument",
+                                ));
+                            } else {
+                                let offset = match args[1].strip_prefix("0x") {
+                                    Some(v) => usize::from_str_radix(v, 16),
+                                    None => args[1].parse(),
+                                };
 
-                            KeyCode::Char(c) => {
-                                editor_command.push(c);
-                            }
+                                match offset {
+                                    Ok(offset) => {
+                                        let cursor = self.buffers.get_current().cursor_index();
+                                        if self.buffers.get_current_mut().goto_byte(offset) {
+                                            self.buffers.push_jump(cursor);
+                                        } else {
+                                            self.message = Some(format!("Offset {} is past the end of the buffer", args[1]));
+                                        }
+                                    }
 
-                            KeyCode::Null => (),
+                                    Err(_) => {
+                                        self.message = Some(format!("Invalid offset `{}`", args[1]));
+                                    }
+                                }
+                            }
+                        }
 
-                            KeyCode::Esc => {
-                                mode = Mode::Normal;
+                        Some("set" | "se") => {
+                            if args.len() == 1 {
+                                self.message = Some(String::from(
+                                    "`set` takes in at least 1 argument",
+                                ));
                             }
-                        },
 
-                        Mode::Insert => match key.code {
-                            KeyCode::Backspace => {
-                                buffers.get_current_mut().backspace();
+                            for arg in args[1..].iter() {
+                                match set_option(self.buffers.get_current_mut(), &mut self.registers, arg) {
+                                    Ok(Some(v)) => self.message = Some(v),
+                                    Ok(None) => (),
+                                    Err(e) => {
+                                        self.message = Some(e);
+                                        break;
+                                    }
+                                }
                             }
+                        }
 
-                            KeyCode::Enter => {
-                                buffers.get_current_mut().enter();
+                        // A line number on its own goes to that line.
+                        Some(v) if args.len() == 1 && v.parse::<usize>().is_ok() => {
+                            let buffer = self.buffers.get_current_mut();
+                            let cursor = buffer.cursor_index();
+                            if buffer.apply_motion(Motion::LastLine, v.parse().ok()) {
+                                self.buffers.push_jump(cursor);
                             }
+                        }
+
+                        Some(v) => {
+                            self.message = Some(format!("`{}` is not a valid command", v));
+                        }
+
+                        None => (),
+                    }
+                }
+
+                KeyCode::Left => (),
+                KeyCode::Right => (),
+                KeyCode::Up => (),
+                KeyCode::Down => (),
+                KeyCode::Home => (),
+                KeyCode::End => (),
+                KeyCode::PageUp => (),
+                KeyCode::PageDown => (),
+                KeyCode::Tab => (),
+                KeyCode::BackTab => (),
+                KeyCode::Delete => (),
+                KeyCode::Insert => (),
+                KeyCode::F(_) => (),
+
+                KeyCode::Char(c) => {
+                    self.editor_command.push(c);
+                }
+
+                KeyCode::Null => (),
+
+                KeyCode::Esc => {
+                    self.mode = Mode::Normal;
+                }
+            },
+
+            // The pattern is typed into `editor_command` like a command, and the cursor
+            // jumps to the first match from where the search started as it changes.
+            Mode::Search { forward } => {
+                let (cursor, vscroll, hscroll) = self.search_origin;
+                let buffer = self.buffers.get_current_mut();
+                buffer.set_cursor_index(cursor);
+                buffer.vscroll = vscroll;
+                buffer.hscroll = hscroll;
+
+                match key.code {
+                    KeyCode::Backspace => {
+                        if self.editor_command.is_empty() {
+                            self.mode = Mode::Normal;
+                        } else {
+                            self.editor_command.pop();
+                        }
+                    }
 
-                            KeyCode::Left => (),
-                            KeyCode::Right => (),
-                            KeyCode::Up => (),
-                            KeyCode::Down => (),
-                            KeyCode::Home => (),
-                            KeyCode::End => (),
-                            KeyCode::PageUp => (),
-                            KeyCode::PageDown => (),
-                            KeyCode::Tab => (),
-                            KeyCode::BackTab => (),
-                            KeyCode::Delete => (),
-                            KeyCode::Insert => (),
-                            KeyCode::F(_) => (),
+                    KeyCode::Enter => {
+                        self.mode = Mode::Normal;
+                        let pattern = if self.editor_command.is_empty() {
+                            self.last_search.as_ref().map(|(regex, _)| regex.as_str().to_owned())
+                        } else {
+                            Some(self.editor_command.clone())
+                        };
 
-                            KeyCode::Char(c) => {
-                                buffers.get_current_mut().char(c)
+                        match pattern.map(|v| search::compile(&v)) {
+                            Some(Ok(regex)) => {
+                                match search_next(buffer, &regex, cursor, forward) {
+                                    Ok(message) => self.message = message,
+                                    Err(e) => {
+                                        self.message = Some(e);
+                                        self.failed = true;
+                                    }
+                                }
+                                if buffer.cursor_index() != cursor {
+                                    self.buffers.push_jump(cursor);
+                                }
+                                self.last_search = Some((regex, forward));
+                                self.highlight_search = true;
                             }
 
-                            KeyCode::Null => (),
+                            Some(Err(e)) => self.message = Some(format!("Invalid pattern: {}", e)),
+                            None => self.message = Some(String::from("No previous search pattern")),
+                        }
+                    }
+
+                    KeyCode::Char(c) => {
+                        self.editor_command.push(c);
+                    }
+
+                    KeyCode::Esc => {
+                        self.mode = Mode::Normal;
+                    }
+
+                    _ => (),
+                }
+
+                if let Mode::Search { .. } = self.mode {
+                    if let Ok(regex) = search::compile(&self.editor_command) {
+                        self.buffers.get_current_mut().search(&regex, cursor, forward);
+                    }
+                }
+            }
+
+            // Confirm mode for `:substitute` asks about every match in turn.
+            Mode::Substitute => {
+                let buffer = self.buffers.get_current_mut();
+                let pending = self.substitution.as_mut().unwrap();
+                match key.code {
+                    KeyCode::Char('y') => pending.replace(buffer),
+                    KeyCode::Char('n') => pending.skip(),
+                    KeyCode::Char('a') => pending.replace_all(buffer),
+                    KeyCode::Char('q') | KeyCode::Esc => {
+                        while pending.current().is_some() {
+                            pending.skip();
+                        }
+                    }
+                    _ => (),
+                }
+
+                match pending.current() {
+                    Some((range, _)) => buffer.set_cursor_index(range.start),
+                    None => {
+                        self.message = Some(self.substitution.take().unwrap().finish(buffer));
+                        self.mode = Mode::Normal;
+                    }
+                }
+            }
+
+            Mode::Insert => match key.code {
+                KeyCode::Backspace => {
+                    self.buffers.get_current_mut().backspace();
+                    if let Some(change) = self.inserting.as_mut() {
+                        change.inserted.push(InsertKey::Backspace);
+                    }
+                }
+
+                KeyCode::Enter => {
+                    self.buffers.get_current_mut().enter();
+                    if let Some(change) = self.inserting.as_mut() {
+                        change.inserted.push(InsertKey::Char('\n'));
+                    }
+                }
+
+                KeyCode::Left => (),
+                KeyCode::Right => (),
+                KeyCode::Up => (),
+                KeyCode::Down => (),
+                KeyCode::Home => (),
+                KeyCode::End => (),
+                KeyCode::PageUp => (),
+                KeyCode::PageDown => (),
+                KeyCode::Tab => (),
+                KeyCode::BackTab => (),
+                KeyCode::Delete => (),
+                KeyCode::Insert => (),
+                KeyCode::F(_) => (),
+
+                KeyCode::Char(c) => {
+                    self.buffers.get_current_mut().char(c);
+                    if let Some(change) = self.inserting.as_mut() {
+                        change.inserted.push(InsertKey::Char(c));
+                    }
+                }
+
+                KeyCode::Null => (),
 
-                            KeyCode::Esc => {
-                                mode = Mode::Normal;
+                KeyCode::Esc => {
+                    self.mode = Mode::Normal;
+                    let buffer = self.buffers.get_current_mut();
+                    if let Some(change) = self.inserting.take() {
+                        // `3i` types the text three times in all.
+                        if let Command::Action {
+                            count: Some(count),
+                            action: Action::Insert,
+                            ..
+                        } = change.command
+                        {
+                            for _ in 1..count {
+                                for key in &change.inserted {
+                                    key.apply(buffer);
+                                }
                             }
-                        },
-                    },
+                        }
+                        self.last_change = Some(change);
+                    }
+                    buffer.commit();
+                }
+            },
+
+            // Keys that do something to the selection only count when no motion is being typed,
+            // so that `fd` finds a `d` rather than deleting. Anything else goes to the parser for
+            // the motions normal mode has.
+            Mode::Visual(kind) => match key.code {
+                KeyCode::Char(c) if self.object_pending.is_some() => {
+                    let around = self.object_pending.take().unwrap();
+                    if let Some(object) = TextObject::from_key(around, c) {
+                        let buffer = self.buffers.get_current_mut();
+                        buffer.select_text_object(object, 1);
+                        if let Some(selection) = buffer.selection() {
+                            self.mode = Mode::Visual(selection.kind);
+                        }
+                    }
+                }
+
+                // `i` and `a` are followed by the text object to select.
+                KeyCode::Char(c @ ('i' | 'a')) if self.parser.is_empty() => {
+                    self.object_pending = Some(c == 'a');
+                }
+
+                KeyCode::Left => self.move_cursor(Motion::Left, None),
+                KeyCode::Down => self.move_cursor(Motion::Down, None),
+                KeyCode::Up => self.move_cursor(Motion::Up, None),
+                KeyCode::Right => self.move_cursor(Motion::Right, None),
+
+                KeyCode::Char('o') if self.parser.is_empty() => {
+                    self.buffers.get_current_mut().swap_selection_ends();
+                }
+
+                KeyCode::Char('"') if self.parser.is_empty() => {
+                    self.awaiting_register = true;
+                }
+
+                // Pressing the key for the current kind of selection leaves visual
+                // mode, and the key for another kind switches to it.
+                KeyCode::Char(c @ ('v' | 'V')) if self.parser.is_empty() => {
+                    let new_kind = if key.modifiers.contains(KeyModifiers::CONTROL) {
+                        SelectionKind::Block
+                    } else if c == 'V' {
+                        SelectionKind::Line
+                    } else {
+                        SelectionKind::Char
+                    };
+
+                    let buffer = self.buffers.get_current_mut();
+                    if new_kind == kind {
+                        buffer.clear_selection();
+                        self.mode = Mode::Normal;
+                    } else {
+                        buffer.set_selection_kind(new_kind);
+                        self.mode = Mode::Visual(new_kind);
+                    }
+                }
+
+                // Esc drops a half typed motion first, and leaves visual mode after that.
+                KeyCode::Esc if !self.parser.is_empty() => self.parser.reset(),
+
+                KeyCode::Esc => {
+                    self.object_pending = None;
+                    self.buffers.get_current_mut().clear_selection();
+                    self.mode = Mode::Normal;
+                }
+
+                KeyCode::Char(':') if self.parser.is_empty() => {
+                    self.buffers.get_current_mut().clear_selection();
+                    self.mode = Mode::Command;
+                    self.editor_command = String::from("'<,'>");
+                }
+
+                KeyCode::Char(c) if self.parser.is_empty() && Operator::from_visual_key(c).is_some() => {
+                    let operator = Operator::from_visual_key(c).unwrap();
+                    let buffer = self.buffers.get_current_mut();
+                    let selection = buffer.selection().unwrap();
+                    buffer.clear_selection();
+                    self.mode = Mode::Normal;
+
+                    match operator.apply(buffer, &selection, &mut self.registers, self.register.take()) {
+                        Ok(v) => self.message = v,
+                        Err(e) => self.message = Some(format!("Could not copy to clipboard: {}", e)),
+                    }
+
+                    if operator.inserts() {
+                        self.mode = Mode::Insert;
+                    } else {
+                        buffer.commit();
+                    }
+                }
+
+                _ => {
+                    if let Some(Parse::Done(Command::Move { count, motion })) =
+                        keys::to_char(&key).map(|c| self.parser.push(c))
+                    {
+                        self.move_cursor(motion, count);
+                    }
+                }
+            },
+
+            Mode::UndoTree { selected } => match key.code {
+                KeyCode::Char('j') | KeyCode::Down => {
+                    let count = self.buffers.get_current().undo_tree().entries().len();
+                    self.mode = Mode::UndoTree { selected: (selected + 1).min(count - 1) };
+                }
+
+                KeyCode::Char('k') | KeyCode::Up => {
+                    self.mode = Mode::UndoTree { selected: selected.saturating_sub(1) };
+                }
+
+                KeyCode::Enter => {
+                    let entries = self.buffers.get_current().undo_tree().entries();
+                    self.buffers.get_current_mut().goto_undo_state(entries[selected].seq);
+                }
+
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    self.mode = Mode::Normal;
+                }
+
+                _ => (),
+            },
+
+            Mode::Registers => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc | KeyCode::Enter => {
+                    self.mode = Mode::Normal;
+                }
+
+                _ => (),
+            },
+        }
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut stdout = io::stdout();
+    let mut terminal = Terminal::new(backend)?;
+    crossterm::terminal::enable_raw_mode()?;
+    terminal.clear()?;
+
+    let mut message = None;
+    let args: Vec<_> = env::args().collect();
+    let buffer = if args.len() > 1 {
+        let name = &args[1];
+        match Buffer::open(name) {
+            Ok(v) => v,
+            Err(e) => {
+                message = Some(format!("Could not open file `{}`: {}", name, e));
+                Buffer::new("[buffer]", false, "")
+            }
+        }
+    } else {
+        Buffer::new("[buffer]", false, "")
+    };
+    let mut editor = Editor::new(Buffers::new(buffer));
+    editor.message = message;
+
+    while editor.running {
+        if let Ok(true) = crossterm::event::poll(Duration::from_millis(10)) {
+            if let Ok(event) = crossterm::event::read() {
+                match event {
+                    crossterm::event::Event::Key(key) => editor.handle_key(key),
 
                     // TODO: mouse stuff
                     crossterm::event::Event::Mouse(_) => (),
@@ -314,35 +1327,165 @@ fn main() -> Result<(), Error> {
             let horizontal = layout::Layout::default()
                 .direction(layout::Direction::Horizontal)
                 .constraints([
-                    layout::Constraint::Length(
-                        1 + ((buffers.get_current().line_count() + 1) as f64)
+                    layout::Constraint::Length(if editor.buffers.get_current().hex().is_some() {
+                        9
+                    } else {
+                        1 + ((editor.buffers.get_current().line_count() + 1) as f64)
                             .log10()
-                            .ceil() as u16,
-                    ),
+                            .ceil() as u16
+                    }),
                     layout::Constraint::Length(1),
                     layout::Constraint::Min(1),
                 ])
                 .split(vertical[0]);
 
-            buffers.get_current_mut().update_scrolls(horizontal[2].width as isize, horizontal[2].height as isize);
-            let buffer = buffers.get_current();
-
-            let text_field = widgets::Paragraph::new(
-                buffer.window(horizontal[2].width as usize, horizontal[2].height as usize)
-                    .map(|v| Spans::from(v.into_iter().map(Span::raw).collect::<Vec<_>>()))
-                    .collect::<Vec<_>>())
-            .alignment(layout::Alignment::Left);
-            f.render_widget(text_field, horizontal[2]);
-
-            let line_numbers = widgets::Block::default().borders(widgets::Borders::RIGHT);
-            let line_numbers = widgets::Paragraph::new(
-                (buffer.vscroll + 1..1 + buffer.line_count().min(buffer.vscroll + horizontal[0].height as usize + 1))
-                    .map(|v| Spans::from(vec![Span::raw(format!("{}", v))]))
-                    .collect::<Vec<_>>(),
-            )
-            .block(line_numbers)
-            .alignment(layout::Alignment::Right);
-            f.render_widget(line_numbers, horizontal[0]);
+            editor.buffers.get_current_mut().update_scrolls(horizontal[2].width as isize, horizontal[2].height as isize);
+            let buffer = editor.buffers.get_current();
+
+            if let Some(hex) = buffer.hex() {
+                let height = horizontal[2].height as usize;
+                let rows: Vec<_> = hex.rows(height).collect();
+                let text_field = widgets::Paragraph::new(
+                    rows.iter()
+                        .map(|row| {
+                            let mut spans = vec![Span::raw(row.hex()), Span::raw("  ")];
+                            for (i, c) in row.ascii().chars().enumerate() {
+                                if row.offset + i == hex.cursor() {
+                                    spans.push(Span::styled(
+                                        c.to_string(),
+                                        Style::default().add_modifier(Modifier::REVERSED),
+                                    ));
+                                } else {
+                                    spans.push(Span::raw(c.to_string()));
+                                }
+                            }
+                            Spans::from(spans)
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .alignment(layout::Alignment::Left);
+                f.render_widget(text_field, horizontal[2]);
+
+                let offsets = widgets::Block::default().borders(widgets::Borders::RIGHT);
+                let offsets = widgets::Paragraph::new(
+                    rows.iter()
+                        .map(|v| Spans::from(vec![Span::raw(format!("{:08x}", v.offset))]))
+                        .collect::<Vec<_>>(),
+                )
+                .block(offsets)
+                .alignment(layout::Alignment::Right);
+                f.render_widget(offsets, horizontal[0]);
+            } else {
+                let search = match editor.mode {
+                    Mode::Search { .. } => search::compile(&editor.editor_command).ok(),
+                    Mode::Substitute => None,
+                    _ if editor.highlight_search => editor.last_search.as_ref().map(|(regex, _)| regex.clone()),
+                    _ => None,
+                };
+                let mut window = buffer.window(horizontal[2].width as usize, horizontal[2].height as usize);
+                if let Some((range, _)) = editor.substitution.as_ref().and_then(|v| v.current()) {
+                    window = window.highlight(range, Highlight::CurrentMatch);
+                }
+                let text_field = widgets::Paragraph::new(
+                    window
+                        .search(search.as_ref().filter(|v| !v.as_str().is_empty()))
+                        .extmarks()
+                        .map(|v| {
+                            Spans::from(
+                                v.into_iter()
+                                    .map(|(text, highlight)| match highlight {
+                                        Highlight::None => Span::raw(text),
+                                        Highlight::Selection => Span::styled(
+                                            text,
+                                            Style::default().add_modifier(Modifier::REVERSED),
+                                        ),
+                                        Highlight::Search => Span::styled(
+                                            text,
+                                            Style::default().bg(Color::Yellow).fg(Color::Black),
+                                        ),
+                                        Highlight::CurrentMatch => Span::styled(
+                                            text,
+                                            Style::default().bg(Color::Red).fg(Color::Black),
+                                        ),
+                                        Highlight::Extmark(style) => Span::styled(
+                                            text,
+                                            match style {
+                                                ExtmarkStyle::Underline => Style::default(),
+                                                ExtmarkStyle::Error => Style::default().fg(Color::Red),
+                                                ExtmarkStyle::Warning => Style::default().fg(Color::Yellow),
+                                                ExtmarkStyle::Info => Style::default().fg(Color::Blue),
+                                            }
+                                            .add_modifier(Modifier::UNDERLINED),
+                                        ),
+                                    })
+                                    .collect::<Vec<_>>(),
+                            )
+                        })
+                        .collect::<Vec<_>>())
+                .alignment(layout::Alignment::Left);
+                f.render_widget(text_field, horizontal[2]);
+
+                let line_numbers = widgets::Block::default().borders(widgets::Borders::RIGHT);
+                let line_numbers = widgets::Paragraph::new(
+                    buffer
+                        .screen_lines(horizontal[2].width as usize, horizontal[0].height as usize)
+                        .into_iter()
+                        .map(|v| Spans::from(vec![Span::raw(v.map(|v| format!("{}", v + 1)).unwrap_or_default())]))
+                        .collect::<Vec<_>>(),
+                )
+                .block(line_numbers)
+                .alignment(layout::Alignment::Right);
+                f.render_widget(line_numbers, horizontal[0]);
+            }
+
+            if let Mode::UndoTree { selected } = editor.mode {
+                let tree = buffer.undo_tree();
+                let items: Vec<_> = tree
+                    .entries()
+                    .into_iter()
+                    .map(|v| {
+                        let age = if v.seq == 0 {
+                            String::from("original")
+                        } else {
+                            format_age(v.time)
+                        };
+                        widgets::ListItem::new(format!(
+                            "{}{} {:>4}  {}{}",
+                            "  ".repeat(v.depth),
+                            if v.current { '@' } else { '*' },
+                            v.seq,
+                            age,
+                            if v.saved { "  [saved]" } else { "" },
+                        ))
+                    })
+                    .collect();
+                let list = widgets::List::new(items)
+                    .block(widgets::Block::default().title("undotree").borders(widgets::Borders::ALL))
+                    .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+                let mut state = widgets::ListState::default();
+                state.select(Some(selected));
+                f.render_widget(widgets::Clear, vertical[0]);
+                f.render_stateful_widget(list, vertical[0], &mut state);
+            }
+
+            if let Mode::Registers = editor.mode {
+                let items: Vec<_> = editor.registers
+                    .iter()
+                    .map(|(name, v)| {
+                        let kind = match v.kind {
+                            SelectionKind::Char => 'c',
+                            SelectionKind::Line => 'l',
+                            SelectionKind::Block => 'b',
+                        };
+                        let text = v.text.replace('\n', "^J").replace('\t', "^I");
+                        widgets::ListItem::new(format!("{}  \"{}  {}", kind, name, text))
+                    })
+                    .collect();
+                let list = widgets::List::new(items)
+                    .block(widgets::Block::default().title("registers").borders(widgets::Borders::ALL));
+                f.render_widget(widgets::Clear, vertical[0]);
+                f.render_widget(list, vertical[0]);
+            }
 
             let command = widgets::Block::default().borders(widgets::Borders::TOP);
             let mut command_data = vec![Spans::from(vec![
@@ -352,13 +1495,45 @@ fn main() -> Result<(), Error> {
                 } else {
                     Span::raw("")
                 },
+                if buffer.mixed_endings() {
+                    Span::raw(" [mixed]")
+                } else if buffer.line_ending() != LineEnding::Lf {
+                    Span::raw(format!(" [{}]", buffer.line_ending().name()))
+                } else {
+                    Span::raw("")
+                },
+                if buffer.hex().is_some() {
+                    Span::raw(" [hex]")
+                } else if buffer.encoding() != encoding_rs::UTF_8 || buffer.bom() {
+                    Span::raw(format!(
+                        " [{}{}]",
+                        buffer.encoding().name().to_lowercase(),
+                        if buffer.bom() { ", bom" } else { "" }
+                    ))
+                } else {
+                    Span::raw("")
+                },
+                match editor.recording {
+                    Some((name, _)) => Span::raw(format!(" [recording @{}]", name)),
+                    None => Span::raw(""),
+                },
             ])];
-            if let Mode::Command = mode {
+            if let Mode::Command = editor.mode {
                 command_data.push(Spans::from(vec![
                     Span::raw(":"),
-                    Span::raw(&editor_command),
+                    Span::raw(&editor.editor_command),
                 ]));
-            } else if let Some(message) = message.as_ref() {
+            } else if let Some((_, replacement)) = editor.substitution.as_ref().and_then(|v| v.current()) {
+                command_data.push(Spans::from(vec![Span::raw(format!(
+                    "replace with {} (y/n/a/q)?",
+                    replacement
+                ))]));
+            } else if let Mode::Search { forward } = editor.mode {
+                command_data.push(Spans::from(vec![
+                    Span::raw(if forward { "/" } else { "?" }),
+                    Span::raw(&editor.editor_command),
+                ]));
+            } else if let Some(message) = editor.message.as_ref() {
                 command_data.push(Spans::from(vec![Span::raw(message)]));
             }
             let command = widgets::Paragraph::new(command_data)
@@ -366,12 +1541,20 @@ fn main() -> Result<(), Error> {
                 .block(command);
             f.render_widget(command, vertical[1]);
 
-            if let Mode::Insert = mode {
+            // Keys of a normal mode command that is not finished yet go on the right.
+            if let Mode::Normal = editor.mode {
+                let pending = widgets::Paragraph::new(vec![Spans::default(), Spans::from(editor.parser.pending())])
+                    .alignment(layout::Alignment::Right)
+                    .block(widgets::Block::default().borders(widgets::Borders::TOP));
+                f.render_widget(pending, vertical[1]);
+            }
+
+            if let Mode::Insert = editor.mode {
                 execute!(stdout, SetCursorShape(CursorShape::Line))
                     .expect("could not set cursor shape");
                 let (x, y) = buffer.cursor_pos(horizontal[2].x as usize, horizontal[2].y as usize);
                 f.set_cursor(x as u16, y as u16);
-            } else if let Mode::Normal = mode {
+            } else if let Mode::Normal | Mode::Visual(_) | Mode::Search { .. } | Mode::Substitute = editor.mode {
                 execute!(stdout, SetCursorShape(CursorShape::Block))
                     .expect("could not set cursor shape");
                 let (x, y) = buffer.cursor_pos(horizontal[2].x as usize, horizontal[2].y as usize);
@@ -380,6 +1563,10 @@ fn main() -> Result<(), Error> {
         })?;
     }
 
+    for buffer in editor.buffers.iter_mut() {
+        let _ = buffer.save_undo_history();
+    }
+
     terminal.clear()?;
     terminal::disable_raw_mode()?;
     execute!(
@@ -391,3 +1578,74 @@ fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_keys(text: &str, keys: &str) -> String {
+        let mut editor = Editor::new(Buffers::new(Buffer::new("test", false, text)));
+        editor.registers.set_clipboard(Box::new(clipboard::MemoryClipboard::default()));
+        for c in keys.chars() {
+            editor.handle_key(keys::from_char(c));
+        }
+        editor.buffers.get_current().to_string()
+    }
+
+    #[test]
+    fn time_travel_arguments() {
+        let parse = |arg| parse_time_travel(Some(&arg));
+        assert!(matches!(parse_time_travel(None), Some(TimeTravel::Steps(1))));
+        assert!(matches!(parse("3"), Some(TimeTravel::Steps(3))));
+        assert!(matches!(parse("2m"), Some(TimeTravel::Time(v)) if v.as_secs() == 120));
+        assert!(matches!(parse("1d"), Some(TimeTravel::Time(v)) if v.as_secs() == 86400));
+        assert!(parse("5\u{e9}").is_none());
+        assert!(parse("\u{e9}").is_none());
+        assert!(parse("h").is_none());
+        assert!(parse("99999999999999999d").is_none());
+    }
+
+    #[test]
+    fn put_with_a_huge_count() {
+        assert_eq!(type_keys("ab\n", "yl3p"), "aaaab\n");
+        assert_eq!(type_keys("ab\n", "yl99999999999999p"), "ab\n");
+    }
+
+    #[test]
+    fn failed_search_stops_a_macro() {
+        assert_eq!(type_keys("a x\nb\nc\n", "qqnddq2@q"), "b\nc\n");
+        assert_eq!(type_keys("a x\nb x\nc\n", "/x\rqqddnq3@q"), "c\n");
+    }
+
+    #[test]
+    fn read_binary_file() {
+        let path = env::temp_dir().join(format!("nu-read-binary-{}", std::process::id()));
+        std::fs::write(&path, [0u8, 1, 2, 0xff, 0]).unwrap();
+        let mut editor = Editor::new(Buffers::new(Buffer::new("test", false, "text\n")));
+        for c in format!(":r {}\r", path.display()).chars() {
+            editor.handle_key(keys::from_char(c));
+        }
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(editor.buffers.get_current().to_string(), "text\n");
+        assert!(editor.message.unwrap().starts_with("Cannot read binary file"));
+    }
+
+    #[test]
+    fn visual_mode_motions() {
+        assert_eq!(type_keys("one two three\n", "vwd"), "wo three\n");
+        assert_eq!(type_keys("one two three\n", "v3ld"), "two three\n");
+        assert_eq!(type_keys("one two three\n", "vtwd"), "wo three\n");
+        assert_eq!(type_keys("one two three\n", "vfdd"), "ne two three\n");
+        assert_eq!(type_keys("one two three\n", "v$hd"), "e\n");
+        assert_eq!(type_keys("a\nb\nc\nd\n", "vGd"), "\n");
+        assert_eq!(type_keys("a\nb\n\nc\n", "v}d"), "c\n");
+        assert_eq!(type_keys("a\nb\nc\nd\n", "V2jd"), "d\n");
+    }
+
+    #[test]
+    fn visual_mode_count_before_operator() {
+        assert_eq!(type_keys("one two\n", "vl2d"), "e two\n");
+        assert_eq!(type_keys("one two\n", "v2\x1bld"), "e two\n");
+    }
+}
@@ -0,0 +1,350 @@
+use ropey::RopeSlice;
+
+use crate::grapheme;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    // `gk` and `gj`, which move by screen row when lines are wrapped.
+    RowUp,
+    RowDown,
+    WordStart { big: bool },
+    WordEnd { big: bool },
+    WordBack { big: bool },
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    // `gg` and `G`, which go to the line given by the count if there is one.
+    FirstLine,
+    LastLine,
+    Find { c: char, forward: bool, till: bool },
+    // `;` and `,` repeat a find but never get stuck on a character that `t` or `T` stopped next to.
+    RepeatFind { c: char, forward: bool, till: bool },
+    ParagraphForward,
+    ParagraphBackward,
+    ScreenTop,
+    ScreenMiddle,
+    ScreenBottom,
+    // `'` followed by a mark goes to its line and `` ` `` to the mark itself.
+    Mark { name: char, line: bool },
+}
+
+// How an operator treats the text between the cursor and the target of a motion.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MotionKind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+impl Motion {
+    pub fn kind(&self) -> MotionKind {
+        match self {
+            Motion::Up
+            | Motion::Down
+            | Motion::FirstLine
+            | Motion::LastLine
+            | Motion::ScreenTop
+            | Motion::ScreenMiddle
+            | Motion::ScreenBottom
+            | Motion::Mark { line: true, .. } => MotionKind::Linewise,
+
+            Motion::WordEnd { .. } | Motion::LineEnd => MotionKind::Inclusive,
+            Motion::Find { forward, .. } | Motion::RepeatFind { forward, .. } if *forward => {
+                MotionKind::Inclusive
+            }
+
+            _ => MotionKind::Exclusive,
+        }
+    }
+
+    // Whether the motion is a jump, which the jump list remembers the start of.
+    pub fn is_jump(&self) -> bool {
+        matches!(
+            self,
+            Motion::FirstLine
+                | Motion::LastLine
+                | Motion::ParagraphForward
+                | Motion::ParagraphBackward
+                | Motion::ScreenTop
+                | Motion::ScreenMiddle
+                | Motion::ScreenBottom
+                | Motion::Mark { .. }
+        )
+    }
+
+    // The motion for `f`, `t`, `F` or `T` followed by `c`.
+    pub fn find(key: char, c: char) -> Option<Motion> {
+        let (forward, till) = match key {
+            'f' => (true, false),
+            't' => (true, true),
+            'F' => (false, false),
+            'T' => (false, true),
+            _ => return None,
+        };
+        Some(Motion::Find { c, forward, till })
+    }
+
+    // The motion `;` makes after this find, or `,` if `reverse` is set.
+    pub fn repeat(&self, reverse: bool) -> Option<Motion> {
+        match *self {
+            Motion::Find { c, forward, till } | Motion::RepeatFind { c, forward, till } => {
+                Some(Motion::RepeatFind {
+                    c,
+                    forward: forward != reverse,
+                    till,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// Which characters make up a word for `w`, `b` and `e`, like vim's `iskeyword`. Letters, digits and
+// `_` always do and filetypes can add more, such as `-` in Lisp and CSS.
+#[derive(Clone, PartialEq, Eq)]
+pub struct WordChars {
+    extra: Vec<char>,
+}
+
+impl WordChars {
+    pub fn for_filetype(filetype: &str) -> Self {
+        let extra = match filetype {
+            "lisp" | "elisp" | "scheme" | "clojure" => "-*+!?<>=/",
+            "css" | "html" => "-",
+            "sh" | "make" => "-.",
+            _ => "",
+        };
+        WordChars {
+            extra: extra.chars().collect(),
+        }
+    }
+
+    // Parses the extra characters as given to `:set iskeyword`.
+    pub fn parse(extra: &str) -> Self {
+        WordChars {
+            extra: extra.chars().filter(|c| !c.is_alphanumeric() && *c != '_').collect(),
+        }
+    }
+
+    pub fn is_word(&self, c: char) -> bool {
+        c.is_alphanumeric() || c == '_' || self.extra.contains(&c)
+    }
+
+    pub fn extra(&self) -> String {
+        self.extra.iter().collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Newline,
+    Space,
+    Word,
+    Punctuation,
+}
+
+fn class(text: &RopeSlice, i: usize, words: &WordChars, big: bool) -> Class {
+    match text.char(i) {
+        '\n' => Class::Newline,
+        c if c.is_whitespace() => Class::Space,
+        _ if big => Class::Word,
+        c if words.is_word(c) => Class::Word,
+        _ => Class::Punctuation,
+    }
+}
+
+// An empty line counts as a word of its own, so word motions stop on it.
+fn is_empty_line(text: &RopeSlice, i: usize) -> bool {
+    i < text.len_chars() && text.char(i) == '\n' && (i == 0 || text.char(i - 1) == '\n')
+}
+
+pub fn word_start(text: &RopeSlice, mut i: usize, words: &WordChars, big: bool) -> usize {
+    let len = text.len_chars();
+    if i >= len {
+        return len;
+    }
+
+    let start = class(text, i, words, big);
+    if start == Class::Word || start == Class::Punctuation {
+        while i < len && class(text, i, words, big) == start {
+            i = grapheme::next_boundary(text, i);
+        }
+    }
+
+    while i < len {
+        match class(text, i, words, big) {
+            Class::Space => i = grapheme::next_boundary(text, i),
+            Class::Newline => {
+                i += 1;
+                if is_empty_line(text, i) {
+                    break;
+                }
+            }
+            _ => break,
+        }
+    }
+    i
+}
+
+pub fn word_end(text: &RopeSlice, i: usize, words: &WordChars, big: bool) -> usize {
+    let len = text.len_chars();
+    let mut i = grapheme::next_boundary(text, i.min(len));
+    while i < len && matches!(class(text, i, words, big), Class::Space | Class::Newline) {
+        i = grapheme::next_boundary(text, i);
+    }
+    if i >= len {
+        return grapheme::prev_boundary(text, len);
+    }
+
+    let end = class(text, i, words, big);
+    loop {
+        let next = grapheme::next_boundary(text, i);
+        if next >= len || class(text, next, words, big) != end {
+            return i;
+        }
+        i = next;
+    }
+}
+
+// The end of the word the cursor is on, or of the run of spaces it is in.
+pub fn current_word_end(text: &RopeSlice, mut i: usize, words: &WordChars, big: bool) -> usize {
+    let len = text.len_chars();
+    let start = class(text, i, words, big);
+    loop {
+        let next = grapheme::next_boundary(text, i);
+        if next >= len || class(text, next, words, big) != start {
+            return i;
+        }
+        i = next;
+    }
+}
+
+pub fn current_word_start(text: &RopeSlice, mut i: usize, words: &WordChars, big: bool) -> usize {
+    let start = class(text, i, words, big);
+    while i > 0 {
+        let prev = grapheme::prev_boundary(text, i);
+        if class(text, prev, words, big) != start {
+            break;
+        }
+        i = prev;
+    }
+    i
+}
+
+pub fn word_back(text: &RopeSlice, i: usize, words: &WordChars, big: bool) -> usize {
+    if i == 0 {
+        return 0;
+    }
+
+    let mut i = grapheme::prev_boundary(text, i);
+    while i > 0 && matches!(class(text, i, words, big), Class::Space | Class::Newline) {
+        if is_empty_line(text, i) {
+            return i;
+        }
+        i = grapheme::prev_boundary(text, i);
+    }
+
+    let start = class(text, i, words, big);
+    if start == Class::Space || start == Class::Newline {
+        return i;
+    }
+    while i > 0 {
+        let prev = grapheme::prev_boundary(text, i);
+        if class(text, prev, words, big) != start {
+            break;
+        }
+        i = prev;
+    }
+    i
+}
+
+// The `count`th occurrence of `c` in the line from `i`, skipping the character next to `i` when
+// `skip_adjacent` is set. Returns where the cursor ends up, which for `till` is next to it.
+pub fn find_in_line(
+    text: &RopeSlice,
+    i: usize,
+    (c, forward, till): (char, bool, bool),
+    count: usize,
+    skip_adjacent: bool,
+) -> Option<usize> {
+    let line = text.char_to_line(i);
+    let line_start = text.line_to_char(line);
+    let line_end = line_start + text.line(line).chars().take_while(|&c| c != '\n').count();
+
+    let mut found = 0;
+    let mut j = i;
+    loop {
+        j = if forward {
+            let next = grapheme::next_boundary(text, j);
+            if next >= line_end {
+                return None;
+            }
+            next
+        } else {
+            if j <= line_start {
+                return None;
+            }
+            grapheme::prev_boundary(text, j)
+        };
+
+        if text.char(j) != c {
+            continue;
+        }
+
+        let target = match (till, forward) {
+            (false, _) => j,
+            (true, true) => grapheme::prev_boundary(text, j),
+            (true, false) => grapheme::next_boundary(text, j),
+        };
+        if skip_adjacent && till && target == i {
+            continue;
+        }
+
+        found += 1;
+        if found == count {
+            return Some(target);
+        }
+    }
+}
+
+// The next empty line after the paragraph the line is in, or the end of the text.
+pub fn paragraph_forward(text: &RopeSlice, mut line: usize) -> usize {
+    let last = text.len_lines() - 1;
+    let empty = |line: usize| text.line(line).chars().all(|c| c == '\n');
+    while line < last && empty(line) {
+        line += 1;
+    }
+    while line < last && !empty(line) {
+        line += 1;
+    }
+
+    if empty(line) {
+        text.line_to_char(line)
+    } else {
+        text.len_chars()
+    }
+}
+
+pub fn paragraph_backward(text: &RopeSlice, mut line: usize) -> usize {
+    let empty = |line: usize| text.line(line).chars().all(|c| c == '\n');
+    while line > 0 && empty(line) {
+        line -= 1;
+    }
+    while line > 0 && !empty(line) {
+        line -= 1;
+    }
+    text.line_to_char(line)
+}
+
+pub fn first_non_blank(text: &RopeSlice, line: usize) -> usize {
+    text.line_to_char(line)
+        + text
+            .line(line)
+            .chars()
+            .take_while(|&c| c == ' ' || c == '\t')
+            .count()
+}
@@ -0,0 +1,684 @@
+use ropey::Rope;
+
+use crate::{
+    buffer::{Buffer, Selection, SelectionKind},
+    grapheme,
+    motion::{self, Motion, MotionKind},
+    operator::Operator,
+    textobject::TextObject,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Insert,
+    Command,
+    Visual(SelectionKind),
+    Search { forward: bool },
+    SearchNext { reverse: bool },
+    Put { after: bool },
+    Undo,
+    Redo,
+    // `.`, which does the last change again.
+    Repeat,
+    // `q` followed by the register to record a macro into, and `@` followed by the one to play.
+    Record(char),
+    Play(char),
+    SetMark(char),
+    JumpBack,
+    JumpForward,
+    PrevBuffer,
+    NextBuffer,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Motion(Motion),
+    // The operator typed twice, as in `dd`, which works on whole lines.
+    Line,
+    TextObject(TextObject),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Move {
+        count: Option<usize>,
+        motion: Motion,
+    },
+    Operate {
+        count: Option<usize>,
+        register: Option<char>,
+        operator: Operator,
+        target: Target,
+    },
+    Action {
+        count: Option<usize>,
+        register: Option<char>,
+        action: Action,
+    },
+}
+
+impl Command {
+    // Whether the command changes the buffer, so that `.` can do it again.
+    pub fn changes(&self) -> bool {
+        match self {
+            Command::Move { .. } => false,
+            Command::Operate { operator, .. } => *operator != Operator::Yank,
+            Command::Action { action, .. } => matches!(action, Action::Insert | Action::Put { .. }),
+        }
+    }
+
+    // The same command with a different count, which is how `.` takes one.
+    pub fn with_count(self, new_count: Option<usize>) -> Command {
+        match self {
+            Command::Move { motion, .. } => Command::Move { count: new_count, motion },
+            Command::Operate {
+                register,
+                operator,
+                target,
+                ..
+            } => Command::Operate {
+                count: new_count,
+                register,
+                operator,
+                target,
+            },
+            Command::Action { register, action, .. } => Command::Action {
+                count: new_count,
+                register,
+                action,
+            },
+        }
+    }
+}
+
+// A key typed in insert mode. The keys are kept rather than the text they made, since backspace
+// can delete text from before the insert started.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InsertKey {
+    Char(char),
+    Backspace,
+}
+
+impl InsertKey {
+    pub fn apply(&self, buffer: &mut Buffer) {
+        match *self {
+            InsertKey::Char(c) => buffer.char(c),
+            InsertKey::Backspace => buffer.backspace(),
+        }
+    }
+}
+
+// The last change made, for `.`: the command and whatever was typed in the insert mode it started.
+#[derive(Clone)]
+pub struct Change {
+    pub command: Command,
+    pub inserted: Vec<InsertKey>,
+}
+
+#[derive(Clone, Copy)]
+enum Binding {
+    Operator(Operator),
+    Motion(Motion),
+    // `f`, `t`, `F` and `T`, which take the character to find as the next key.
+    Find(char),
+    RepeatFind { reverse: bool },
+    // `'` and `` ` ``, which take the mark to go to as the next key.
+    Mark { line: bool },
+    // Actions that take a register or mark name as the next key, like `q` and `m`.
+    TakesChar(fn(char) -> Action),
+    Action(Action),
+    // Keys that are short for an operator and a target, like `x` for `dl`.
+    Shortcut(Operator, Target),
+}
+
+// Every key sequence normal mode understands. Adding an operator, motion or action only takes an
+// entry here and the code to run it.
+const BINDINGS: &[(&str, Binding)] = &[
+    ("d", Binding::Operator(Operator::Delete)),
+    ("c", Binding::Operator(Operator::Change)),
+    ("y", Binding::Operator(Operator::Yank)),
+    (">", Binding::Operator(Operator::Indent)),
+    ("<", Binding::Operator(Operator::Outdent)),
+    ("gu", Binding::Operator(Operator::Lowercase)),
+    ("gU", Binding::Operator(Operator::Uppercase)),
+    ("g~", Binding::Operator(Operator::ToggleCase)),
+    ("=", Binding::Operator(Operator::Format)),
+    ("h", Binding::Motion(Motion::Left)),
+    ("l", Binding::Motion(Motion::Right)),
+    ("k", Binding::Motion(Motion::Up)),
+    ("j", Binding::Motion(Motion::Down)),
+    ("gk", Binding::Motion(Motion::RowUp)),
+    ("gj", Binding::Motion(Motion::RowDown)),
+    ("w", Binding::Motion(Motion::WordStart { big: false })),
+    ("W", Binding::Motion(Motion::WordStart { big: true })),
+    ("e", Binding::Motion(Motion::WordEnd { big: false })),
+    ("E", Binding::Motion(Motion::WordEnd { big: true })),
+    ("b", Binding::Motion(Motion::WordBack { big: false })),
+    ("B", Binding::Motion(Motion::WordBack { big: true })),
+    ("0", Binding::Motion(Motion::LineStart)),
+    ("^", Binding::Motion(Motion::FirstNonBlank)),
+    ("$", Binding::Motion(Motion::LineEnd)),
+    ("gg", Binding::Motion(Motion::FirstLine)),
+    ("G", Binding::Motion(Motion::LastLine)),
+    ("}", Binding::Motion(Motion::ParagraphForward)),
+    ("{", Binding::Motion(Motion::ParagraphBackward)),
+    ("H", Binding::Motion(Motion::ScreenTop)),
+    ("M", Binding::Motion(Motion::ScreenMiddle)),
+    ("L", Binding::Motion(Motion::ScreenBottom)),
+    ("f", Binding::Find('f')),
+    ("t", Binding::Find('t')),
+    ("F", Binding::Find('F')),
+    ("T", Binding::Find('T')),
+    (";", Binding::RepeatFind { reverse: false }),
+    (",", Binding::RepeatFind { reverse: true }),
+    ("i", Binding::Action(Action::Insert)),
+    (":", Binding::Action(Action::Command)),
+    ("v", Binding::Action(Action::Visual(SelectionKind::Char))),
+    ("V", Binding::Action(Action::Visual(SelectionKind::Line))),
+    ("\x16", Binding::Action(Action::Visual(SelectionKind::Block))),
+    ("/", Binding::Action(Action::Search { forward: true })),
+    ("?", Binding::Action(Action::Search { forward: false })),
+    ("n", Binding::Action(Action::SearchNext { reverse: false })),
+    ("N", Binding::Action(Action::SearchNext { reverse: true })),
+    ("p", Binding::Action(Action::Put { after: true })),
+    ("P", Binding::Action(Action::Put { after: false })),
+    ("u", Binding::Action(Action::Undo)),
+    ("\x12", Binding::Action(Action::Redo)),
+    (".", Binding::Action(Action::Repeat)),
+    ("q", Binding::TakesChar(Action::Record)),
+    ("@", Binding::TakesChar(Action::Play)),
+    ("m", Binding::TakesChar(Action::SetMark)),
+    ("'", Binding::Mark { line: true }),
+    ("`", Binding::Mark { line: false }),
+    ("\x0f", Binding::Action(Action::JumpBack)),
+    ("\t", Binding::Action(Action::JumpForward)),
+    ("[", Binding::Action(Action::PrevBuffer)),
+    ("]", Binding::Action(Action::NextBuffer)),
+    ("x", Binding::Shortcut(Operator::Delete, Target::Motion(Motion::Right))),
+    ("X", Binding::Shortcut(Operator::Delete, Target::Motion(Motion::Left))),
+    ("D", Binding::Shortcut(Operator::Delete, Target::Motion(Motion::LineEnd))),
+    ("C", Binding::Shortcut(Operator::Change, Target::Motion(Motion::LineEnd))),
+    ("s", Binding::Shortcut(Operator::Change, Target::Motion(Motion::Right))),
+    ("S", Binding::Shortcut(Operator::Change, Target::Line)),
+    ("Y", Binding::Shortcut(Operator::Yank, Target::Line)),
+];
+
+pub enum Parse {
+    Pending,
+    Done(Command),
+    Invalid,
+}
+
+// Collects the keys of a normal mode command until they make up a whole one. Commands have the
+// form `[count]["x][operator][count][motion|text object]`, where the operator and motion can each
+// take more than one key.
+#[derive(Default)]
+pub struct Parser {
+    keys: String,
+    last_find: Option<Motion>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The keys typed so far, for showing in the command bar.
+    pub fn pending(&self) -> String {
+        self.keys
+            .chars()
+            .map(|c| {
+                if c.is_control() {
+                    format!("^{}", (c as u8 + b'@') as char)
+                } else {
+                    c.to_string()
+                }
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.keys.clear();
+    }
+
+    // Adds a key, given as the character it types with control characters standing for Ctrl
+    // combinations, and tries to parse the keys so far.
+    pub fn push(&mut self, key: char) -> Parse {
+        self.keys.push(key);
+        let result = self.parse();
+        if !matches!(result, Parse::Pending) {
+            self.keys.clear();
+        }
+        result
+    }
+
+    fn parse(&mut self) -> Parse {
+        let keys = self.keys.clone();
+        let (count, rest) = split_count(&keys);
+
+        let (register, rest) = match rest.strip_prefix('"') {
+            Some(rest) => match rest.chars().next() {
+                Some(c) => (Some(c), &rest[c.len_utf8()..]),
+                None => return Parse::Pending,
+            },
+            None => (None, rest),
+        };
+        let (count, rest) = match count_after(count, rest) {
+            Some(v) => v,
+            None => return Parse::Invalid,
+        };
+
+        let (binding, key, rest) = match lookup(rest) {
+            Lookup::Found(binding, key, rest) => (binding, key, rest),
+            Lookup::Pending => return Parse::Pending,
+            Lookup::Invalid => return Parse::Invalid,
+        };
+
+        match binding {
+            Binding::Operator(operator) => {
+                let (motion_count, rest) = split_count(rest);
+                let count = multiply(count, motion_count);
+                if rest.is_empty() {
+                    return Parse::Pending;
+                }
+
+                // Doubling the operator, or its last key as in `guu`, works on lines.
+                let last = key.chars().last().unwrap().to_string();
+                if rest == key || rest == last {
+                    return Parse::Done(Command::Operate {
+                        count,
+                        register,
+                        operator,
+                        target: Target::Line,
+                    });
+                } else if key.starts_with(rest) || last.starts_with(rest) {
+                    return Parse::Pending;
+                }
+
+                // `i` and `a` start a text object rather than inserting after an operator.
+                let mut chars = rest.chars();
+                if let Some(c @ ('i' | 'a')) = chars.next() {
+                    return match (chars.next(), chars.next()) {
+                        (None, _) => Parse::Pending,
+                        (Some(key), None) => match TextObject::from_key(c == 'a', key) {
+                            Some(object) => Parse::Done(Command::Operate {
+                                count,
+                                register,
+                                operator,
+                                target: Target::TextObject(object),
+                            }),
+                            None => Parse::Invalid,
+                        },
+                        _ => Parse::Invalid,
+                    };
+                }
+
+                match self.motion(rest) {
+                    Ok(Some(motion)) => Parse::Done(Command::Operate {
+                        count,
+                        register,
+                        operator,
+                        target: Target::Motion(motion),
+                    }),
+                    Ok(None) => Parse::Pending,
+                    Err(()) => Parse::Invalid,
+                }
+            }
+
+            Binding::Shortcut(operator, target) => Parse::Done(Command::Operate {
+                count,
+                register,
+                operator,
+                target,
+            }),
+
+            Binding::Action(action) => Parse::Done(Command::Action {
+                count,
+                register,
+                action,
+            }),
+
+            Binding::TakesChar(action) => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (None, _) => Parse::Pending,
+                    (Some(c), None) => Parse::Done(Command::Action {
+                        count,
+                        register,
+                        action: action(c),
+                    }),
+                    _ => Parse::Invalid,
+                }
+            }
+
+            Binding::Motion(_) | Binding::Find(_) | Binding::RepeatFind { .. } | Binding::Mark { .. } => {
+                match self.motion(&keys[keys.len() - key.len() - rest.len()..]) {
+                    Ok(Some(motion)) => Parse::Done(Command::Move { count, motion }),
+                    Ok(None) => Parse::Pending,
+                    Err(()) => Parse::Invalid,
+                }
+            }
+        }
+    }
+
+    // Parses keys that should be a whole motion.
+    fn motion(&mut self, keys: &str) -> Result<Option<Motion>, ()> {
+        match lookup(keys) {
+            Lookup::Found(Binding::Motion(motion), _, "") => Ok(Some(motion)),
+
+            Lookup::Found(Binding::Find(key), _, rest) => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (None, _) => Ok(None),
+                    (Some(c), None) => {
+                        let motion = Motion::find(key, c).ok_or(())?;
+                        self.last_find = Some(motion);
+                        Ok(Some(motion))
+                    }
+                    _ => Err(()),
+                }
+            }
+
+            Lookup::Found(Binding::Mark { line }, _, rest) => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (None, _) => Ok(None),
+                    (Some(name), None) => Ok(Some(Motion::Mark { name, line })),
+                    _ => Err(()),
+                }
+            }
+
+            Lookup::Found(Binding::RepeatFind { reverse }, _, "") => self
+                .last_find
+                .and_then(|v| v.repeat(reverse))
+                .map(Some)
+                .ok_or(()),
+
+            Lookup::Pending => Ok(None),
+            _ => Err(()),
+        }
+    }
+}
+
+enum Lookup<'a> {
+    Found(Binding, &'static str, &'a str),
+    Pending,
+    Invalid,
+}
+
+fn lookup(keys: &str) -> Lookup<'_> {
+    if keys.is_empty() {
+        return Lookup::Pending;
+    }
+
+    for &(key, binding) in BINDINGS {
+        if let Some(rest) = keys.strip_prefix(key) {
+            return Lookup::Found(binding, key, rest);
+        }
+    }
+
+    if BINDINGS.iter().any(|(key, _)| key.starts_with(keys)) {
+        Lookup::Pending
+    } else {
+        Lookup::Invalid
+    }
+}
+
+// Splits off a count. A leading `0` is the motion rather than part of a count.
+fn split_count(keys: &str) -> (Option<usize>, &str) {
+    if keys.starts_with('0') {
+        return (None, keys);
+    }
+
+    let digits = keys.find(|c: char| !c.is_ascii_digit()).unwrap_or(keys.len());
+    match keys[..digits].parse() {
+        Ok(v) => (Some(v), &keys[digits..]),
+        Err(_) => (None, keys),
+    }
+}
+
+// A count can also come after the register, as in `"a3yy`.
+fn count_after(count: Option<usize>, keys: &str) -> Option<(Option<usize>, &str)> {
+    let (after, rest) = split_count(keys);
+    match (count, after) {
+        (Some(_), Some(_)) => None,
+        (count, after) => Some((count.or(after), rest)),
+    }
+}
+
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.saturating_mul(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// The part of the buffer an operator works on when given a target.
+pub fn selection(
+    buffer: &Buffer,
+    operator: Operator,
+    target: Target,
+    count: Option<usize>,
+) -> Option<Selection> {
+    let cursor = buffer.cursor_index();
+    let text = buffer.text();
+    let motion = match target {
+        Target::Line => return Some(buffer.line_selection(count.unwrap_or(1))),
+        Target::TextObject(object) => {
+            return object.select(&text.slice(..), cursor, count.unwrap_or(1), buffer.word_chars());
+        }
+        Target::Motion(motion) => motion,
+    };
+
+    // `cw` on a word changes up to its end rather than the start of the next one.
+    let slice = text.slice(..);
+    if let Motion::WordStart { big } = motion {
+        if operator == Operator::Change && cursor < text.len_chars() && !text.char(cursor).is_whitespace() {
+            let words = buffer.word_chars();
+            let first = motion::current_word_end(&slice, cursor, words, big);
+            let end = (1..count.unwrap_or(1)).fold(first, |i, _| motion::word_end(&slice, i, words, big));
+            return Some(Selection {
+                kind: SelectionKind::Char,
+                anchor: cursor,
+                cursor: end,
+            });
+        }
+    }
+
+    let mut target = buffer.motion_target(motion, count)?;
+    match motion.kind() {
+        MotionKind::Linewise => Some(Selection {
+            kind: SelectionKind::Line,
+            anchor: cursor,
+            cursor: target,
+        }),
+
+        MotionKind::Inclusive => Some(Selection {
+            kind: SelectionKind::Char,
+            anchor: cursor,
+            cursor: target,
+        }),
+
+        MotionKind::Exclusive => {
+            // A word motion that crosses into another line stops at the end of the line the last
+            // word was on, so `dw` on the last word of a line does not join it with the next.
+            if let Motion::WordStart { .. } = motion {
+                if target > cursor && text.char_to_line(target) > text.char_to_line(cursor) {
+                    let mut end = target;
+                    while end > cursor + 1 && text.char(end - 1).is_whitespace() {
+                        end -= 1;
+                    }
+                    target = target.min(line_end(text, text.char_to_line(end - 1)));
+                }
+            }
+
+            // The newline before a motion that ends at the start of a line is left alone.
+            let (start, mut end) = (cursor.min(target), cursor.max(target));
+            let end_line = text.char_to_line(end);
+            let start_line = text.char_to_line(start);
+            if end_line > start_line && text.line_to_char(end_line) == end {
+                // Starting at or before the first non-blank makes it work on whole lines, like vim.
+                if start <= motion::first_non_blank(&slice, start_line) {
+                    return Some(Selection {
+                        kind: SelectionKind::Line,
+                        anchor: start,
+                        cursor: text.line_to_char(end_line - 1),
+                    });
+                }
+                end -= 1;
+            }
+            if start == end {
+                return None;
+            }
+
+            Some(Selection {
+                kind: SelectionKind::Char,
+                anchor: start,
+                cursor: grapheme::prev_boundary(&slice, end),
+            })
+        }
+    }
+}
+
+fn line_end(text: &Rope, line: usize) -> usize {
+    let start = text.line_to_char(line);
+    start + text.line(line).chars().take_while(|&c| c != '\n').count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clipboard::MemoryClipboard, registers::Registers};
+
+    // Parses keys that should make a whole command only once the last one is typed.
+    fn parse(keys: &str) -> Option<Command> {
+        let mut parser = Parser::new();
+        let mut chars = keys.chars().peekable();
+        while let Some(c) = chars.next() {
+            match parser.push(c) {
+                Parse::Done(command) if chars.peek().is_none() => return Some(command),
+                Parse::Pending if chars.peek().is_some() => (),
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    // Runs an operator with the cursor on the first `|`, which is taken out of the text, and
+    // returns the text left and the text put in the unnamed register.
+    fn operate(text: &str, keys: &str) -> (String, String) {
+        let i = text.find('|').unwrap();
+        let mut buffer = Buffer::new("test", false, &text.replacen('|', "", 1));
+        buffer.set_cursor_index(i);
+        let mut registers = Registers::new(Box::new(MemoryClipboard::default()));
+        if let Some(Command::Operate { count, register, operator, target }) = parse(keys) {
+            if let Some(selection) = selection(&buffer, operator, target, count) {
+                operator.apply(&mut buffer, &selection, &mut registers, register).unwrap();
+            }
+        }
+        let yanked = registers.get('"').unwrap().map(|v| v.text).unwrap_or_default();
+        (buffer.to_string(), yanked)
+    }
+
+    fn operated(text: &str, keys: &str) -> String {
+        operate(text, keys).0
+    }
+
+    #[test]
+    fn counts_and_registers() {
+        let command = parse("\"a2d3w");
+        assert!(
+            command
+                == Some(Command::Operate {
+                    count: Some(6),
+                    register: Some('a'),
+                    operator: Operator::Delete,
+                    target: Target::Motion(Motion::WordStart { big: false }),
+                })
+        );
+        assert!(parse("\"a3yy").is_some());
+        assert!(parse("2\"a3yy").is_none());
+        assert!(parse("10j") == Some(Command::Move { count: Some(10), motion: Motion::Down }));
+        assert!(parse("0") == Some(Command::Move { count: None, motion: Motion::LineStart }));
+    }
+
+    #[test]
+    fn operator_keys() {
+        let line = |operator| Some(Command::Operate { count: None, register: None, operator, target: Target::Line });
+        assert!(parse("dd") == line(Operator::Delete));
+        assert!(parse("gUU") == line(Operator::Uppercase));
+        assert!(parse("gUgU") == line(Operator::Uppercase));
+        assert!(parse("g~~") == line(Operator::ToggleCase));
+        assert!(parse("dgg").is_some());
+        assert!(parse("dfx").is_some());
+        assert!(parse("dz").is_none());
+        assert!(parse("diz").is_none());
+        assert!(parse("qa") == Some(Command::Action { count: None, register: None, action: Action::Record('a') }));
+    }
+
+    #[test]
+    fn repeat_find() {
+        let mut parser = Parser::new();
+        parser.push('t');
+        parser.push('x');
+        let repeat = |parser: &mut Parser, c| match parser.push(c) {
+            Parse::Done(Command::Move { motion, .. }) => Some(motion),
+            _ => None,
+        };
+        assert!(repeat(&mut parser, ';') == Some(Motion::RepeatFind { c: 'x', forward: true, till: true }));
+        assert!(repeat(&mut parser, ',') == Some(Motion::RepeatFind { c: 'x', forward: false, till: true }));
+        assert!(repeat(&mut Parser::new(), ';').is_none());
+    }
+
+    #[test]
+    fn delete_words() {
+        assert_eq!(operate("one |two three\n", "dw"), (String::from("one three\n"), String::from("two ")));
+        assert_eq!(operated("one |two three\n", "2dw"), "one \n");
+        assert_eq!(operated("one t|wo\nthree\n", "dw"), "one t\nthree\n");
+        assert_eq!(operated("one |two three\n", "de"), "one  three\n");
+        assert_eq!(operated("one two |three\n", "db"), "one three\n");
+    }
+
+    #[test]
+    fn change_word_stops_at_its_end() {
+        assert_eq!(operate("|one two\n", "cw"), (String::from(" two\n"), String::from("one")));
+        assert_eq!(operated("|one two three\n", "c2w"), " three\n");
+        assert_eq!(operated("|  one\n", "cw"), "one\n");
+    }
+
+    #[test]
+    fn linewise() {
+        assert_eq!(operate("a\n|b\nc\n", "dd"), (String::from("a\nc\n"), String::from("b\n")));
+        assert_eq!(operated("a\n|b\nc\nd\n", "2dd"), "a\nd\n");
+        assert_eq!(operated("a\n|b\nc\nd\n", "dj"), "a\nd\n");
+        assert_eq!(operated("a\nb\n|c\n", "dk"), "a\n");
+        assert_eq!(operated("a\n|b\nc\n", "dG"), "a\n");
+        assert_eq!(operated("|a\n\nb\n", "d}"), "\nb\n");
+        assert_eq!(operated("a\n|b\n", ">>"), "a\n    b\n");
+        assert_eq!(operated("|ab cd\n", "gUU"), "AB CD\n");
+    }
+
+    #[test]
+    fn inclusive_and_exclusive() {
+        assert_eq!(operated("a|bcdef\n", "dfd"), "aef\n");
+        assert_eq!(operated("a|bcdef\n", "dtd"), "adef\n");
+        assert_eq!(operated("abcd|ef\n", "dFb"), "aef\n");
+        assert_eq!(operated("a|bcdef\n", "d$"), "a\n");
+        assert_eq!(operated("abc|def\n", "d0"), "def\n");
+        assert_eq!(operated("a|bc\n", "3x"), "a\n");
+        assert_eq!(operated("a|bc\n", "dfz"), "abc\n");
+    }
+
+    #[test]
+    fn text_objects() {
+        assert_eq!(operate("f(a, |b)\n", "di("), (String::from("f()\n"), String::from("a, b")));
+        assert_eq!(operated("say \"h|i\" now\n", "da\""), "say now\n");
+        assert_eq!(operated("one t|wo three\n", "d2aw"), "one\n");
+        assert_eq!(operated("one t|wo three\n", "gUiw"), "one TWO three\n");
+        assert_eq!(operated("a\n\n|b\n\nc\n", "dap"), "a\n\nc\n");
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+
+use crate::{
+    buffer::{Buffer, Case, Selection, SelectionKind},
+    registers::{Register, Registers},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+    Lowercase,
+    Uppercase,
+    ToggleCase,
+    Format,
+}
+
+impl Operator {
+    // The operator a key applies to the selection in visual mode.
+    pub fn from_visual_key(c: char) -> Option<Operator> {
+        Some(match c {
+            'd' | 'x' => Operator::Delete,
+            'c' | 's' => Operator::Change,
+            'y' => Operator::Yank,
+            '>' => Operator::Indent,
+            '<' => Operator::Outdent,
+            'u' => Operator::Lowercase,
+            'U' => Operator::Uppercase,
+            '~' => Operator::ToggleCase,
+            '=' => Operator::Format,
+            _ => return None,
+        })
+    }
+
+    // Whether the operator leaves the editor in insert mode.
+    pub fn inserts(&self) -> bool {
+        *self == Operator::Change
+    }
+
+    // Applies the operator to a selection, putting any text it removes or copies into `register`.
+    // Returns a message to show, if there is one.
+    pub fn apply(
+        &self,
+        buffer: &mut Buffer,
+        selection: &Selection,
+        registers: &mut Registers,
+        register: Option<char>,
+    ) -> io::Result<Option<String>> {
+        let mut message = None;
+        match self {
+            Operator::Delete => {
+                let text = buffer.delete_selection(selection);
+                let lines = text.lines().count();
+                if lines > 2 {
+                    message = Some(format!("{} fewer lines", lines));
+                }
+                registers.delete(register, Register { text, kind: selection.kind })?;
+            }
+
+            Operator::Change => {
+                let text = buffer.change_selection(selection);
+                registers.delete(register, Register { text, kind: selection.kind })?;
+            }
+
+            Operator::Yank => {
+                let text = buffer.selection_text(selection);
+                let lines = text.lines().count();
+                if lines > 2 {
+                    message = Some(format!("{} lines yanked", lines));
+                }
+                if selection.kind != SelectionKind::Line {
+                    buffer.set_cursor_index(selection.anchor.min(selection.cursor));
+                }
+                registers.yank(register, Register { text, kind: selection.kind })?;
+            }
+
+            Operator::Indent => buffer.indent_selection(selection, false),
+            Operator::Outdent => buffer.indent_selection(selection, true),
+            Operator::Lowercase => buffer.change_case_selection(selection, Case::Lower),
+            Operator::Uppercase => buffer.change_case_selection(selection, Case::Upper),
+            Operator::ToggleCase => buffer.change_case_selection(selection, Case::Toggle),
+            Operator::Format => buffer.reindent_selection(selection),
+        }
+        Ok(message)
+    }
+}
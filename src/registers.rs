@@ -0,0 +1,300 @@
+use std::io;
+
+use crate::{
+    buffer::SelectionKind,
+    clipboard::{ClipboardProvider, ClipboardTarget},
+};
+
+#[derive(Clone)]
+pub struct Register {
+    pub text: String,
+    pub kind: SelectionKind,
+}
+
+// Registers work like vim's. Yanks go to register 0, deletes of more than part of a line are
+// pushed onto registers 1 to 9 and smaller deletes go to `-`. Naming a register sends the text
+// there instead, the uppercase name appends to it, and `_` throws the text away. The unnamed
+// register `"` always holds whatever was last yanked or deleted. `+` and `*` are the system
+// clipboard and primary selection.
+pub struct Registers {
+    unnamed: Option<Register>,
+    named: Vec<Option<Register>>,
+    numbered: Vec<Option<Register>>,
+    small_delete: Option<Register>,
+    clipboard: Box<dyn ClipboardProvider>,
+    copied: [Option<Register>; 2],
+}
+
+impl Registers {
+    pub fn new(clipboard: Box<dyn ClipboardProvider>) -> Self {
+        Registers {
+            unnamed: None,
+            named: vec![None; 26],
+            numbered: vec![None; 10],
+            small_delete: None,
+            clipboard,
+            copied: [None, None],
+        }
+    }
+
+    pub fn is_valid(name: char) -> bool {
+        matches!(name, '"' | '_' | '-' | '+' | '*' | 'a'..='z' | 'A'..='Z' | '0'..='9')
+    }
+
+    pub fn clipboard(&self) -> &dyn ClipboardProvider {
+        self.clipboard.as_ref()
+    }
+
+    pub fn set_clipboard(&mut self, clipboard: Box<dyn ClipboardProvider>) {
+        self.clipboard = clipboard;
+        self.copied = [None, None];
+    }
+
+    pub fn get(&mut self, name: char) -> io::Result<Option<Register>> {
+        Ok(match name {
+            '"' => self.unnamed.clone(),
+            '-' => self.small_delete.clone(),
+            'a'..='z' | 'A'..='Z' => {
+                self.named[(name.to_ascii_lowercase() as u8 - b'a') as usize].clone()
+            }
+            '0'..='9' => self.numbered[(name as u8 - b'0') as usize].clone(),
+            '+' | '*' => {
+                let target = clipboard_target(name);
+                let text = match self.clipboard.paste(target)? {
+                    Some(v) => v,
+                    None => return Ok(None),
+                };
+
+                // Text that nu copied itself keeps its kind. Anything else is taken to be lines
+                // if it ends in a newline.
+                match &self.copied[target as usize] {
+                    Some(copied) if copied.text == text => Some(copied.clone()),
+                    _ => Some(Register {
+                        kind: if text.ends_with('\n') {
+                            SelectionKind::Line
+                        } else {
+                            SelectionKind::Char
+                        },
+                        text,
+                    }),
+                }
+            }
+            _ => None,
+        })
+    }
+
+    pub fn yank(&mut self, name: Option<char>, register: Register) -> io::Result<()> {
+        match name {
+            Some('_') => Ok(()),
+            Some(name) if name != '"' => self.set(name, register),
+            _ => {
+                self.numbered[0] = Some(register.clone());
+                self.unnamed = Some(register);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn delete(&mut self, name: Option<char>, register: Register) -> io::Result<()> {
+        match name {
+            Some('_') => Ok(()),
+            Some(name) if name != '"' => self.set(name, register),
+            _ => {
+                if register.kind == SelectionKind::Char && !register.text.contains('\n') {
+                    self.small_delete = Some(register.clone());
+                } else {
+                    self.numbered.pop();
+                    self.numbered.insert(1, Some(register.clone()));
+                }
+                self.unnamed = Some(register);
+                Ok(())
+            }
+        }
+    }
+
+    // Stores the keys of a recorded macro. Unlike a yank, this leaves the unnamed register alone.
+    pub fn record(&mut self, name: char, keys: String) -> io::Result<()> {
+        let register = Register {
+            text: keys,
+            kind: SelectionKind::Char,
+        };
+        match name {
+            '"' => self.unnamed = Some(register),
+            '0'..='9' => self.numbered[(name as u8 - b'0') as usize] = Some(register),
+            _ => {
+                let unnamed = self.unnamed.take();
+                self.set(name, register)?;
+                self.unnamed = unnamed;
+            }
+        }
+        Ok(())
+    }
+
+    // Every register that holds something, in the order `:registers` lists them.
+    pub fn iter(&self) -> impl Iterator<Item = (char, &Register)> {
+        let unnamed = self.unnamed.iter().map(|v| ('"', v));
+        let numbered = self
+            .numbered
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.as_ref().map(|v| ((b'0' + i as u8) as char, v)));
+        let named = self
+            .named
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.as_ref().map(|v| ((b'a' + i as u8) as char, v)));
+        let small_delete = self.small_delete.iter().map(|v| ('-', v));
+        unnamed.chain(numbered).chain(named).chain(small_delete)
+    }
+
+    fn set(&mut self, name: char, register: Register) -> io::Result<()> {
+        if let '+' | '*' = name {
+            let target = clipboard_target(name);
+            self.clipboard.copy(target, &register.text)?;
+            self.copied[target as usize] = Some(register.clone());
+            self.unnamed = Some(register);
+            return Ok(());
+        }
+
+        let register = match name {
+            'a'..='z' => Some(register),
+            'A'..='Z' => {
+                let i = (name.to_ascii_lowercase() as u8 - b'a') as usize;
+                Some(match self.named[i].take() {
+                    Some(mut old) => {
+                        // Appending lines to characterwise text turns it into lines.
+                        if register.kind == SelectionKind::Line && old.kind == SelectionKind::Char {
+                            old.text.push('\n');
+                            old.kind = SelectionKind::Line;
+                        } else if old.kind == SelectionKind::Block {
+                            old.text.push('\n');
+                        }
+                        old.text.push_str(&register.text);
+                        if old.kind == SelectionKind::Line && !old.text.ends_with('\n') {
+                            old.text.push('\n');
+                        }
+                        old
+                    }
+                    None => register,
+                })
+            }
+            _ => None,
+        };
+
+        if let Some(register) = register {
+            let i = (name.to_ascii_lowercase() as u8 - b'a') as usize;
+            self.named[i] = Some(register.clone());
+            self.unnamed = Some(register);
+        }
+        Ok(())
+    }
+}
+
+fn clipboard_target(name: char) -> ClipboardTarget {
+    if name == '*' {
+        ClipboardTarget::Primary
+    } else {
+        ClipboardTarget::Clipboard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::MemoryClipboard;
+
+    fn registers() -> Registers {
+        Registers::new(Box::new(MemoryClipboard::default()))
+    }
+
+    fn chars(text: &str) -> Register {
+        Register {
+            text: text.to_owned(),
+            kind: SelectionKind::Char,
+        }
+    }
+
+    fn lines(text: &str) -> Register {
+        Register {
+            text: text.to_owned(),
+            kind: SelectionKind::Line,
+        }
+    }
+
+    fn text(registers: &mut Registers, name: char) -> Option<String> {
+        registers.get(name).unwrap().map(|v| v.text)
+    }
+
+    #[test]
+    fn clipboard_keeps_kind() {
+        let mut registers = registers();
+        registers.yank(Some('+'), lines("one\ntwo\n")).unwrap();
+        let register = registers.get('+').unwrap().unwrap();
+        assert_eq!(register.text, "one\ntwo\n");
+        assert!(register.kind == SelectionKind::Line);
+
+        registers.yank(Some('*'), chars("word")).unwrap();
+        let register = registers.get('*').unwrap().unwrap();
+        assert_eq!(register.text, "word");
+        assert!(register.kind == SelectionKind::Char);
+        assert_eq!(text(&mut registers, '"').as_deref(), Some("word"));
+
+        // The two clipboards are separate.
+        assert_eq!(text(&mut registers, '+').as_deref(), Some("one\ntwo\n"));
+    }
+
+    #[test]
+    fn clipboard_text_from_elsewhere() {
+        let mut clipboard = MemoryClipboard::default();
+        clipboard.copy(ClipboardTarget::Clipboard, "line\n").unwrap();
+        clipboard.copy(ClipboardTarget::Primary, "part").unwrap();
+        let mut registers = Registers::new(Box::new(clipboard));
+        assert!(registers.get('+').unwrap().unwrap().kind == SelectionKind::Line);
+        assert!(registers.get('*').unwrap().unwrap().kind == SelectionKind::Char);
+    }
+
+    #[test]
+    fn yanks_and_deletes_shift_numbered_registers() {
+        let mut registers = registers();
+        registers.yank(None, chars("yanked")).unwrap();
+        assert_eq!(text(&mut registers, '0').as_deref(), Some("yanked"));
+        assert_eq!(text(&mut registers, '"').as_deref(), Some("yanked"));
+
+        registers.delete(None, lines("first\n")).unwrap();
+        registers.delete(None, lines("second\n")).unwrap();
+        assert_eq!(text(&mut registers, '1').as_deref(), Some("second\n"));
+        assert_eq!(text(&mut registers, '2').as_deref(), Some("first\n"));
+        assert_eq!(text(&mut registers, '0').as_deref(), Some("yanked"));
+
+        // Deleting part of a line goes to `-` and leaves the numbered registers alone.
+        registers.delete(None, chars("bit")).unwrap();
+        assert_eq!(text(&mut registers, '-').as_deref(), Some("bit"));
+        assert_eq!(text(&mut registers, '1').as_deref(), Some("second\n"));
+        assert_eq!(text(&mut registers, '"').as_deref(), Some("bit"));
+
+        for i in 0..10 {
+            registers.delete(None, lines(&format!("{}\n", i))).unwrap();
+        }
+        assert_eq!(text(&mut registers, '1').as_deref(), Some("9\n"));
+        assert_eq!(text(&mut registers, '9').as_deref(), Some("1\n"));
+    }
+
+    #[test]
+    fn named_registers() {
+        let mut registers = registers();
+        registers.yank(Some('a'), chars("one")).unwrap();
+        registers.yank(Some('A'), lines("two\n")).unwrap();
+        let register = registers.get('a').unwrap().unwrap();
+        assert_eq!(register.text, "one\ntwo\n");
+        assert!(register.kind == SelectionKind::Line);
+        assert_eq!(text(&mut registers, '0'), None);
+
+        registers.yank(None, chars("kept")).unwrap();
+        registers.delete(Some('_'), chars("gone")).unwrap();
+        assert_eq!(text(&mut registers, '"').as_deref(), Some("kept"));
+
+        registers.record('q', String::from("dd")).unwrap();
+        assert_eq!(text(&mut registers, 'q').as_deref(), Some("dd"));
+        assert_eq!(text(&mut registers, '"').as_deref(), Some("kept"));
+    }
+}
@@ -0,0 +1,204 @@
+use std::ops::Range;
+
+use regex::{Regex, RegexBuilder};
+use ropey::Rope;
+
+// Compiles a search pattern. Like vim with `smartcase`, a pattern is case sensitive only if it
+// contains an uppercase letter, not counting escapes such as `\S`. `^` and `$` match at the start
+// and end of every line.
+pub fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    build(pattern, !has_uppercase(pattern))
+}
+
+pub fn build(pattern: &str, case_insensitive: bool) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(case_insensitive)
+        .multi_line(true)
+        .build()
+}
+
+fn has_uppercase(pattern: &str) -> bool {
+    let mut escaped = false;
+    let mut uppercase = false;
+    for c in pattern.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c.is_uppercase() {
+            uppercase = true;
+        }
+    }
+    uppercase
+}
+
+// Every match in `range` of `text` as ranges of chars. Matches can span lines.
+pub fn matches(text: &Rope, regex: &Regex, range: Range<usize>) -> Vec<Range<usize>> {
+    let slice = text.slice(range.clone());
+    let haystack = slice.to_string();
+    regex
+        .find_iter(&haystack)
+        .map(|v| range.start + slice.byte_to_char(v.start())..range.start + slice.byte_to_char(v.end()))
+        .collect()
+}
+
+// The first match after `from` going forward, or the last one before it going backward. Returns
+// the match and whether the search wrapped around the end of the text to find it.
+pub fn find(text: &Rope, regex: &Regex, from: usize, forward: bool) -> Option<(Range<usize>, bool)> {
+    let end = text.len_chars() + 1;
+    if forward {
+        match first_match(text, regex, from + 1..end) {
+            Some(v) => Some((v, false)),
+            None => first_match(text, regex, 0..from + 1).map(|v| (v, true)),
+        }
+    } else {
+        match last_match(text, regex, 0..from) {
+            Some(v) => Some((v, false)),
+            None => last_match(text, regex, from..end).map(|v| (v, true)),
+        }
+    }
+}
+
+// Searches go through the text this many lines at a time, so that a search near the cursor does
+// not have to copy out the whole of a large file.
+const WINDOW_LINES: usize = 1000;
+// Lines past the end of a window that are searched along with it, so that matches starting in
+// the window but running past its end are found whole. This is also the limit on how far a match
+// can run past the edge of a window: one that needs more lines than this is either cut short or
+// not found at all.
+const OVERLAP_LINES: usize = 100;
+
+// The matches that start in both `lines` and `chars`.
+fn window(text: &Rope, regex: &Regex, lines: Range<usize>, chars: Range<usize>) -> Vec<Range<usize>> {
+    let last = text.len_lines();
+    let start = text.line_to_char(lines.start);
+    let end = text.line_to_char((lines.end + OVERLAP_LINES).min(last));
+    let limit = if lines.end >= last {
+        chars.end
+    } else {
+        text.line_to_char(lines.end).min(chars.end)
+    };
+
+    let slice = text.slice(start..end);
+    let haystack = slice.to_string();
+    let mut at = slice.char_to_byte(chars.start.clamp(start, end) - start);
+    let mut found = Vec::new();
+    while at <= haystack.len() {
+        let v = match regex.find_at(&haystack, at) {
+            Some(v) => v,
+            None => break,
+        };
+        let range = start + slice.byte_to_char(v.start())..start + slice.byte_to_char(v.end());
+        if range.start >= limit {
+            break;
+        }
+        found.push(range);
+
+        // An empty match would be found again at the same place.
+        at = match haystack[v.end()..].chars().next() {
+            _ if v.end() > v.start() => v.end(),
+            Some(c) => v.end() + c.len_utf8(),
+            None => haystack.len() + 1,
+        };
+    }
+    found
+}
+
+// The first match starting in `chars`.
+fn first_match(text: &Rope, regex: &Regex, chars: Range<usize>) -> Option<Range<usize>> {
+    let mut line = text.char_to_line(chars.start.min(text.len_chars()));
+    while line < text.len_lines() && text.line_to_char(line) < chars.end {
+        if let Some(v) = window(text, regex, line..line + WINDOW_LINES, chars.clone()).into_iter().next() {
+            return Some(v);
+        }
+        line += WINDOW_LINES;
+    }
+    None
+}
+
+// The last match starting in `chars`.
+fn last_match(text: &Rope, regex: &Regex, chars: Range<usize>) -> Option<Range<usize>> {
+    if chars.is_empty() {
+        return None;
+    }
+
+    let mut end = text.char_to_line((chars.end - 1).min(text.len_chars())) + 1;
+    loop {
+        let start = end.saturating_sub(WINDOW_LINES);
+        if let Some(v) = window(text, regex, start..end, chars.clone()).pop() {
+            return Some(v);
+        }
+        if start == 0 || text.line_to_char(start) <= chars.start {
+            return None;
+        }
+        end = start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_str(text: &str, pattern: &str, from: usize, forward: bool) -> Option<(Range<usize>, bool)> {
+        find(&Rope::from_str(text), &compile(pattern).unwrap(), from, forward)
+    }
+
+    #[test]
+    fn forward_and_backward() {
+        let text = "one two one two one";
+        assert_eq!(find_str(text, "two", 0, true), Some((4..7, false)));
+        assert_eq!(find_str(text, "two", 4, true), Some((12..15, false)));
+        assert_eq!(find_str(text, "two", 12, true), Some((4..7, true)));
+        assert_eq!(find_str(text, "one", 16, false), Some((8..11, false)));
+        assert_eq!(find_str(text, "one", 0, false), Some((16..19, true)));
+        assert_eq!(find_str(text, "three", 0, true), None);
+    }
+
+    #[test]
+    fn only_match_is_under_the_cursor() {
+        assert_eq!(find_str("abc", "b", 1, true), Some((1..2, true)));
+        assert_eq!(find_str("abc", "b", 1, false), Some((1..2, true)));
+    }
+
+    #[test]
+    fn anchors_match_at_every_line() {
+        let text = "a\nb\nc";
+        assert_eq!(find_str(text, "^", 0, true), Some((2..2, false)));
+        assert_eq!(find_str(text, "$", 1, true), Some((3..3, false)));
+        assert_eq!(find_str(text, "^c", 0, false), Some((4..5, true)));
+    }
+
+    #[test]
+    fn searches_past_one_window() {
+        let mut text = "x\n".repeat(WINDOW_LINES * 2 + 10);
+        text.push_str("needle\n");
+        let rope = Rope::from_str(&text);
+        let regex = compile("needle").unwrap();
+        let at = (WINDOW_LINES * 2 + 10) * 2;
+        assert_eq!(find(&rope, &regex, 0, true), Some((at..at + 6, false)));
+        assert_eq!(find(&rope, &regex, at + 6, true), Some((at..at + 6, true)));
+        assert_eq!(find(&rope, &regex, 0, false), Some((at..at + 6, true)));
+        assert_eq!(find(&rope, &regex, rope.len_chars(), false), Some((at..at + 6, false)));
+    }
+
+    #[test]
+    fn matches_across_a_window_edge() {
+        let mut text = "x\n".repeat(WINDOW_LINES - 1);
+        text.push_str("start\nend\n");
+        let at = (WINDOW_LINES - 1) * 2;
+        assert_eq!(find_str(&text, "start\nend", 0, true), Some((at..at + 9, false)));
+        assert_eq!(find_str(&text, "start\nend", text.len(), false), Some((at..at + 9, false)));
+    }
+
+    #[test]
+    fn matches_past_the_overlap_are_not_found() {
+        let pattern = "start\n(x\n)*end";
+        let long = |lines| format!("{}start\n{}end\n", "x\n".repeat(WINDOW_LINES - 1), "x\n".repeat(lines));
+        let at = (WINDOW_LINES - 1) * 2;
+
+        let text = long(OVERLAP_LINES - 2);
+        let len = 6 + (OVERLAP_LINES - 2) * 2 + 3;
+        assert_eq!(find_str(&text, pattern, 0, true), Some((at..at + len, false)));
+        assert_eq!(find_str(&long(OVERLAP_LINES), pattern, 0, true), None);
+    }
+}
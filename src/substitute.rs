@@ -0,0 +1,443 @@
+use std::ops::Range;
+
+use regex::Regex;
+
+use crate::{buffer::Buffer, search};
+
+#[derive(Clone, Copy)]
+pub enum Address {
+    // A 1-based line number.
+    Line(usize),
+    Current,
+    Last,
+    SelectionStart,
+    SelectionEnd,
+}
+
+#[derive(Clone, Copy)]
+pub enum LineRange {
+    All,
+    Lines(Address, Address),
+}
+
+impl LineRange {
+    // Resolves the range to 0-based lines, both of which are included in it.
+    pub fn resolve(&self, buffer: &Buffer) -> Result<(usize, usize), String> {
+        let (start, end) = match *self {
+            LineRange::All => return Ok((0, buffer.line_count() - 1)),
+            LineRange::Lines(start, end) => (start, end),
+        };
+
+        let resolve = |address| {
+            let text = buffer.text();
+            let selection = buffer.last_selection();
+            let line = match address {
+                Address::Line(v) => v.saturating_sub(1),
+                Address::Current => text.char_to_line(buffer.cursor_index()),
+                Address::Last => buffer.line_count() - 1,
+                Address::SelectionStart | Address::SelectionEnd => {
+                    let selection = selection.ok_or_else(|| String::from("No previous selection"))?;
+                    let (start, end) = (
+                        selection.anchor.min(selection.cursor),
+                        selection.anchor.max(selection.cursor),
+                    );
+                    let i = if let Address::SelectionStart = address { start } else { end };
+                    text.char_to_line(i.min(text.len_chars()))
+                }
+            };
+
+            if line >= buffer.line_count() {
+                Err(format!("Line {} is past the end of the buffer", line + 1))
+            } else {
+                Ok(line)
+            }
+        };
+
+        let (start, end) = (resolve(start)?, resolve(end)?);
+        Ok((start.min(end), start.max(end)))
+    }
+}
+
+pub struct Substitute {
+    pub range: LineRange,
+    // An empty pattern means the last search pattern.
+    pub pattern: String,
+    pub replacement: String,
+    pub global: bool,
+    pub ignore_case: bool,
+    pub confirm: bool,
+}
+
+impl Substitute {
+    pub fn regex(&self, last_search: Option<&Regex>) -> Result<Regex, String> {
+        let pattern = match (self.pattern.as_str(), last_search) {
+            ("", Some(v)) => v.as_str(),
+            ("", None) => return Err(String::from("No previous search pattern")),
+            (v, _) => v,
+        };
+
+        let regex = if self.ignore_case {
+            search::build(pattern, true)
+        } else {
+            search::compile(pattern)
+        };
+        regex.map_err(|e| format!("Invalid pattern: {}", e))
+    }
+}
+
+// Whether a command line is a `:substitute`, with or without a valid range and pattern.
+pub fn is_substitute(command: &str) -> bool {
+    let rest = parse_range(command).map(|(_, v)| v).unwrap_or(command);
+    parse_name(rest).is_some()
+}
+
+// Parses `[range]s[ubstitute]/pattern/replacement/[flags]`. Any delimiter that is not a letter,
+// digit, backslash or space works in place of `/`.
+pub fn parse(command: &str) -> Result<Substitute, String> {
+    let (range, rest) = parse_range(command)?;
+    let rest = parse_name(rest).ok_or_else(|| format!("`{}` is not a valid command", command))?;
+
+    let mut chars = rest.chars();
+    let delimiter = match chars.next() {
+        Some(v) => v,
+        None => return Err(String::from("`substitute` needs a pattern")),
+    };
+    let rest = chars.as_str();
+
+    let (pattern, rest) = split_delimited(rest, delimiter);
+    let (replacement, flags) = match rest {
+        Some(v) => split_delimited(v, delimiter),
+        None => (String::new(), None),
+    };
+
+    let mut substitute = Substitute {
+        range,
+        pattern,
+        replacement: expansion(&replacement),
+        global: false,
+        ignore_case: false,
+        confirm: false,
+    };
+    for flag in flags.unwrap_or("").trim().chars() {
+        match flag {
+            'g' => substitute.global = true,
+            'i' => substitute.ignore_case = true,
+            'c' => substitute.confirm = true,
+            _ => return Err(format!("Invalid flag `{}`", flag)),
+        }
+    }
+    Ok(substitute)
+}
+
+fn parse_name(command: &str) -> Option<&str> {
+    let name_end = command
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(command.len());
+    let (name, rest) = command.split_at(name_end);
+    let valid_delimiter = rest
+        .chars()
+        .next()
+        .map(|c| !c.is_alphanumeric() && c != '\\' && c != ' ' && c != '"')
+        .unwrap_or(true);
+    if !name.is_empty() && "substitute".starts_with(name) && valid_delimiter {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+fn parse_range(command: &str) -> Result<(LineRange, &str), String> {
+    if let Some(rest) = command.strip_prefix('%') {
+        return Ok((LineRange::All, rest));
+    }
+
+    let (start, rest) = match parse_address(command)? {
+        Some(v) => v,
+        None => return Ok((LineRange::Lines(Address::Current, Address::Current), command)),
+    };
+    match rest.strip_prefix(',') {
+        Some(rest) => match parse_address(rest)? {
+            Some((end, rest)) => Ok((LineRange::Lines(start, end), rest)),
+            None => Err(String::from("Missing the end of the range")),
+        },
+        None => Ok((LineRange::Lines(start, start), rest)),
+    }
+}
+
+fn parse_address(text: &str) -> Result<Option<(Address, &str)>, String> {
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    if digits > 0 {
+        let line = text[..digits]
+            .parse()
+            .map_err(|_| format!("Invalid line number `{}`", &text[..digits]))?;
+        return Ok(Some((Address::Line(line), &text[digits..])));
+    }
+
+    for (prefix, address) in [
+        (".", Address::Current),
+        ("$", Address::Last),
+        ("'<", Address::SelectionStart),
+        ("'>", Address::SelectionEnd),
+    ] {
+        if let Some(rest) = text.strip_prefix(prefix) {
+            return Ok(Some((address, rest)));
+        }
+    }
+    Ok(None)
+}
+
+// Splits off text up to an unescaped delimiter, unescaping the delimiter itself. Returns None for
+// the rest if there was no closing delimiter.
+fn split_delimited(text: &str, delimiter: char) -> (String, Option<&str>) {
+    let mut result = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delimiter {
+            return (result, Some(&text[i + c.len_utf8()..]));
+        } else if c == '\\' {
+            match chars.next() {
+                Some((_, c)) if c == delimiter => result.push(c),
+                Some((_, c)) => {
+                    result.push('\\');
+                    result.push(c);
+                }
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    (result, None)
+}
+
+// Turns a vim style replacement into the syntax `Captures::expand` takes: `&` and `\0` are the
+// whole match, `\1` to `\9` are groups, and `\r`, `\n` and `\t` are a newline and a tab.
+fn expansion(replacement: &str) -> String {
+    let mut result = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => result.push_str("${0}"),
+            '$' => result.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(c @ '0'..='9') => {
+                    result.push_str("${");
+                    result.push(c);
+                    result.push('}');
+                }
+                Some('r' | 'n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('$') => result.push_str("$$"),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            },
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+struct Match {
+    range: Range<usize>,
+    replacement: String,
+    line: usize,
+}
+
+// A substitution in progress. Every match is found up front, and then each one is either replaced
+// or skipped in order, which lets confirm mode ask about them one at a time.
+pub struct Substitution {
+    matches: Vec<Match>,
+    next: usize,
+    offset: isize,
+    count: usize,
+    lines: Vec<usize>,
+    last_line: Option<usize>,
+}
+
+impl Substitution {
+    pub fn new(buffer: &Buffer, regex: &Regex, lines: (usize, usize), substitute: &Substitute) -> Self {
+        let text = buffer.text();
+        let start = text.line_to_char(lines.0);
+        let end = text.line_to_char(lines.1) + text.line(lines.1).len_chars();
+        let slice = text.slice(start..end);
+        let haystack = slice.to_string();
+
+        let mut matches: Vec<Match> = Vec::new();
+        for captures in regex.captures_iter(&haystack) {
+            let whole = captures.get(0).unwrap();
+            let range = start + slice.byte_to_char(whole.start())..start + slice.byte_to_char(whole.end());
+            let line = text.char_to_line(range.start);
+            // `^` and `$` can match just after the last line's break, which is on the next line.
+            if line > lines.1 {
+                break;
+            }
+            if !substitute.global && matches.last().map(|v| v.line == line).unwrap_or(false) {
+                continue;
+            }
+
+            let mut replacement = String::new();
+            captures.expand(&substitute.replacement, &mut replacement);
+            matches.push(Match {
+                range,
+                replacement,
+                line,
+            });
+        }
+
+        Substitution {
+            matches,
+            next: 0,
+            offset: 0,
+            count: 0,
+            lines: Vec::new(),
+            last_line: None,
+        }
+    }
+
+    // The next match to replace, adjusted for the replacements made so far, and what it will be
+    // replaced with.
+    pub fn current(&self) -> Option<(Range<usize>, &str)> {
+        self.matches.get(self.next).map(|v| {
+            let start = (v.range.start as isize + self.offset) as usize;
+            (start..start + v.range.len(), v.replacement.as_str())
+        })
+    }
+
+    pub fn replace(&mut self, buffer: &mut Buffer) {
+        if let Some((range, replacement)) = self.current() {
+            let replacement = replacement.to_owned();
+            self.offset += replacement.chars().count() as isize - range.len() as isize;
+            buffer.replace(range.clone(), &replacement);
+            buffer.set_cursor_index(range.start);
+            self.last_line = Some(buffer.text().char_to_line(range.start));
+
+            let line = self.matches[self.next].line;
+            if self.lines.last() != Some(&line) {
+                self.lines.push(line);
+            }
+            self.count += 1;
+        }
+        self.next += 1;
+    }
+
+    pub fn skip(&mut self) {
+        self.next += 1;
+    }
+
+    pub fn replace_all(&mut self, buffer: &mut Buffer) {
+        while self.current().is_some() {
+            self.replace(buffer);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+
+    // Ends the substitution, leaving the cursor at the start of the last line that changed, and
+    // describes what was done.
+    pub fn finish(self, buffer: &mut Buffer) -> String {
+        if let Some(line) = self.last_line {
+            buffer.set_cursor_index(buffer.text().line_to_char(line));
+        }
+        buffer.commit();
+
+        format!(
+            "{} substitution{} on {} line{}",
+            self.count,
+            if self.count == 1 { "" } else { "s" },
+            self.lines.len(),
+            if self.lines.len() == 1 { "" } else { "s" },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn substitute(text: &str, command: &str) -> String {
+        let mut buffer = Buffer::new("test", false, text);
+        let substitute = parse(command).unwrap();
+        let regex = substitute.regex(None).unwrap();
+        let lines = substitute.range.resolve(&buffer).unwrap();
+        Substitution::new(&buffer, &regex, lines, &substitute).replace_all(&mut buffer);
+        buffer.to_string()
+    }
+
+    #[test]
+    fn line_start_stays_in_range() {
+        assert_eq!(substitute("a\nb\nc\n", "s/^/# /"), "# a\nb\nc\n");
+        assert_eq!(substitute("a\nb\nc\n", "1,2s/^/# /"), "# a\n# b\nc\n");
+    }
+
+    #[test]
+    fn line_end_stays_in_range() {
+        assert_eq!(substitute("a\nb\nc\n", "1,2s/$/;/"), "a;\nb;\nc\n");
+        assert_eq!(substitute("a\nb\nc\n", "%s/$/;/"), "a;\nb;\nc;\n");
+    }
+
+    #[test]
+    fn global_flag() {
+        assert_eq!(substitute("aaa\naa\n", "%s/a/b/"), "baa\nba\n");
+        assert_eq!(substitute("aaa\naa\n", "%s/a/b/g"), "bbb\nbb\n");
+    }
+
+    #[test]
+    fn captures() {
+        assert_eq!(substitute("key = value\n", r"s/(\w+) = (\w+)/\2 = \1/"), "value = key\n");
+    }
+
+    #[test]
+    fn replacement_syntax() {
+        assert_eq!(substitute("cost 5\n", r"s/\d/$&.00 \\ \t|/"), "cost $5.00 \\ \t|\n");
+        assert_eq!(substitute("a,b\n", r"s/,/\r/"), "a\nb\n");
+        assert_eq!(substitute("a/b\n", r"s#/#\##"), "a#b\n");
+        assert_eq!(substitute("a/b\n", r"s/\//-/"), "a-b\n");
+    }
+
+    #[test]
+    fn ranges() {
+        let text = "x\nx\nx\nx\n";
+        assert_eq!(substitute(text, "2,3s/x/y/"), "x\ny\ny\nx\n");
+        assert_eq!(substitute(text, "3,2s/x/y/"), "x\ny\ny\nx\n");
+        assert_eq!(substitute(text, ".,$s/x/y/"), "y\ny\ny\ny\n");
+        assert_eq!(substitute(text, "$s/x/y/"), "x\nx\nx\ny\n");
+
+        let buffer = Buffer::new("test", false, text);
+        assert!(parse("5s/x/y/").unwrap().range.resolve(&buffer).is_err());
+        assert!(parse("'<,'>s/x/y/").unwrap().range.resolve(&buffer).is_err());
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(substitute("Aa\n", "s/a/b/gi"), "bb\n");
+        assert!(parse("s/a/b/gc").unwrap().confirm);
+        assert!(parse("s/a/b/x").is_err());
+        assert!(parse("s").is_err());
+        assert!(parse("1,s/a/b/").is_err());
+        assert!(is_substitute("%substitute/a/b/"));
+        assert!(!is_substitute("set wrap"));
+    }
+
+    #[test]
+    fn confirm_one_at_a_time() {
+        let mut buffer = Buffer::new("test", false, "a a\na\n");
+        let substitute = parse("%s/a/b/gc").unwrap();
+        let regex = substitute.regex(None).unwrap();
+        let mut substitution = Substitution::new(&buffer, &regex, (0, 1), &substitute);
+        assert_eq!(substitution.current(), Some((0..1, "b")));
+        substitution.skip();
+        substitution.replace(&mut buffer);
+        assert_eq!(substitution.current(), Some((4..5, "b")));
+        substitution.replace(&mut buffer);
+        assert!(substitution.current().is_none());
+        assert_eq!(substitution.finish(&mut buffer), "2 substitutions on 2 lines");
+        assert_eq!(buffer.to_string(), "a b\nb\n");
+        assert_eq!(buffer.cursor(), (1, 0));
+
+        // The whole substitution is undone at once.
+        buffer.undo();
+        assert_eq!(buffer.to_string(), "a a\na\n");
+    }
+}
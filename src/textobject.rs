@@ -0,0 +1,566 @@
+use ropey::RopeSlice;
+
+use crate::{
+    buffer::{Selection, SelectionKind},
+    grapheme,
+    motion::{self, WordChars},
+};
+
+// A piece of text around the cursor that an operator or visual mode can select as a whole. The
+// inner kind leaves out the surrounding whitespace, quotes, brackets or tags and the around kind
+// takes them in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TextObject {
+    Word { big: bool, around: bool },
+    Quote { quote: char, around: bool },
+    Bracket { open: char, close: char, around: bool },
+    Tag { around: bool },
+    Paragraph { around: bool },
+}
+
+impl TextObject {
+    // The object for `i` (or `a` if `around` is set) followed by `c`.
+    pub fn from_key(around: bool, c: char) -> Option<TextObject> {
+        Some(match c {
+            'w' => TextObject::Word { big: false, around },
+            'W' => TextObject::Word { big: true, around },
+            '"' | '\'' | '`' => TextObject::Quote { quote: c, around },
+            '(' | ')' | 'b' => TextObject::Bracket { open: '(', close: ')', around },
+            '{' | '}' | 'B' => TextObject::Bracket { open: '{', close: '}', around },
+            '[' | ']' => TextObject::Bracket { open: '[', close: ']', around },
+            '<' | '>' => TextObject::Bracket { open: '<', close: '>', around },
+            't' => TextObject::Tag { around },
+            'p' => TextObject::Paragraph { around },
+            _ => return None,
+        })
+    }
+
+    // Whether a bigger count selects the object the current one is nested in.
+    pub fn nests(&self) -> bool {
+        matches!(self, TextObject::Bracket { .. } | TextObject::Tag { .. })
+    }
+
+    // The text the object covers at `i`. A count selects that many words or paragraphs, or the
+    // `count`th enclosing pair of brackets or tags.
+    pub fn select(&self, text: &RopeSlice, i: usize, count: usize, words: &WordChars) -> Option<Selection> {
+        if i >= text.len_chars() {
+            return None;
+        }
+
+        let count = count.max(1);
+        match *self {
+            TextObject::Word { big, around } => Some(word(text, i, count, words, big, around)),
+            TextObject::Quote { quote, around } => quoted(text, i, quote, around),
+            TextObject::Bracket { open, close, around } => bracket(text, i, (open, close), count, around),
+            TextObject::Tag { around } => tag(text, i, count, around),
+            TextObject::Paragraph { around } => Some(paragraph(text, i, count, around)),
+        }
+    }
+}
+
+fn chars(start: usize, end: usize) -> Selection {
+    Selection {
+        kind: SelectionKind::Char,
+        anchor: start,
+        cursor: end,
+    }
+}
+
+fn is_space(c: char) -> bool {
+    c.is_whitespace() && c != '\n'
+}
+
+// The end of the run of spaces starting after `i`, if there is one.
+fn spaces_after(text: &RopeSlice, i: usize) -> Option<usize> {
+    let next = grapheme::next_boundary(text, i);
+    if next < text.len_chars() && is_space(text.char(next)) {
+        Some(motion::current_word_end(text, next, &WordChars::parse(""), false))
+    } else {
+        None
+    }
+}
+
+fn spaces_before(text: &RopeSlice, i: usize) -> Option<usize> {
+    if i > 0 && is_space(text.char(i - 1)) {
+        Some(motion::current_word_start(text, i - 1, &WordChars::parse(""), false))
+    } else {
+        None
+    }
+}
+
+// Words and the runs of spaces between them each count as one for `iw`. `aw` takes a word with
+// the spaces after it, or before it if there are none after.
+fn word(text: &RopeSlice, i: usize, count: usize, words: &WordChars, big: bool, around: bool) -> Selection {
+    let len = text.len_chars();
+    let on_space = is_space(text.char(i));
+    let start = motion::current_word_start(text, i, words, big);
+    let mut end = motion::current_word_end(text, i, words, big);
+
+    // The next word or run of spaces on from `end`, skipping a line break.
+    let next = |end: usize| {
+        let mut next = grapheme::next_boundary(text, end);
+        if next < len && text.char(next) == '\n' {
+            next += 1;
+        }
+        (next < len).then(|| motion::current_word_end(text, next, words, big))
+    };
+
+    if !around {
+        for _ in 1..count {
+            match next(end) {
+                Some(v) => end = v,
+                None => break,
+            }
+        }
+        return chars(start, end);
+    }
+
+    let mut start = start;
+    if on_space {
+        // On spaces, each one counts with the word after it.
+        for n in 0..count {
+            if n > 0 {
+                match spaces_after(text, end) {
+                    Some(v) => end = v,
+                    None => break,
+                }
+            }
+            match next(end) {
+                Some(v) => end = v,
+                None => break,
+            }
+        }
+    } else {
+        for n in 0..count {
+            if n > 0 {
+                match next(end) {
+                    Some(v) => end = v,
+                    None => break,
+                }
+            }
+            if let Some(v) = spaces_after(text, end) {
+                end = v;
+            }
+        }
+
+        if !is_space(text.char(end)) {
+            if let Some(v) = spaces_before(text, start) {
+                start = v;
+            }
+        }
+    }
+    chars(start, end)
+}
+
+// Quotes only pair up within a line, and a backslash escapes one. The cursor on a quote takes the
+// string that quote opens or closes, and outside of any string it takes the next one on the line.
+fn quoted(text: &RopeSlice, i: usize, quote: char, around: bool) -> Option<Selection> {
+    let line = text.char_to_line(i);
+    let line_start = text.line_to_char(line);
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (j, c) in text.line(line).chars().enumerate() {
+        if c == quote && !escaped {
+            quotes.push(line_start + j);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .map(|v| (v[0], v[1]))
+        .find(|&(open, close)| i <= close && (i >= open || !quotes.contains(&i)))?;
+
+    if around {
+        let (mut start, mut end) = (open, close);
+        match spaces_after(text, close) {
+            Some(v) => end = v,
+            None => start = spaces_before(text, open).unwrap_or(open),
+        }
+        Some(chars(start, end))
+    } else if close > open + 1 {
+        Some(chars(open + 1, close - 1))
+    } else {
+        None
+    }
+}
+
+// Finds the `count`th pair of brackets around `i`, counting the pair the cursor is on.
+fn enclosing(text: &RopeSlice, i: usize, (open, close): (char, char), count: usize) -> Option<(usize, usize)> {
+    let mut remaining = count;
+    let mut depth = 0;
+    let mut start = None;
+    for j in (0..=i).rev() {
+        let c = text.char(j);
+        if c == close && j != i {
+            depth += 1;
+        } else if c == open && depth > 0 {
+            depth -= 1;
+        } else if c == open {
+            remaining -= 1;
+            if remaining == 0 {
+                start = Some(j);
+                break;
+            }
+        }
+    }
+    let start = start?;
+
+    let mut depth = 0;
+    for j in start + 1..text.len_chars() {
+        let c = text.char(j);
+        if c == open {
+            depth += 1;
+        } else if c == close && depth > 0 {
+            depth -= 1;
+        } else if c == close {
+            return Some((start, j));
+        }
+    }
+    None
+}
+
+// The inside of a pair of brackets that spans lines leaves out the line break after the opening
+// bracket and the indentation before the closing one, so that `di{` on a block leaves the braces
+// on their own lines. When that leaves whole lines, they are selected as lines.
+fn bracket(text: &RopeSlice, i: usize, pair: (char, char), count: usize, around: bool) -> Option<Selection> {
+    let (open, close) = enclosing(text, i, pair, count)?;
+    if around {
+        return Some(chars(open, close));
+    }
+
+    let mut start = open + 1;
+    let mut end = close;
+    let close_line = text.char_to_line(close);
+    let close_line_start = text.line_to_char(close_line);
+    let close_on_own_line = close_line > text.char_to_line(open)
+        && text.slice(close_line_start..close).chars().all(is_space);
+    if close_on_own_line {
+        end = close_line_start - 1;
+    }
+    if start < end && text.char(start) == '\n' {
+        start += 1;
+    }
+    if start >= end {
+        return None;
+    }
+
+    if close_on_own_line && text.line_to_char(text.char_to_line(start)) == start {
+        Some(Selection {
+            kind: SelectionKind::Line,
+            anchor: start,
+            cursor: end - 1,
+        })
+    } else {
+        Some(chars(start, grapheme::prev_boundary(text, end)))
+    }
+}
+
+struct Tag {
+    start: usize,
+    end: usize,
+    name: String,
+    closing: bool,
+}
+
+impl Tag {
+    // The tag between `<` at `start` and `>` at `end`, leaving out comments, declarations and tags
+    // that close themselves.
+    fn parse(start: usize, end: usize, body: &str) -> Option<Tag> {
+        if body.starts_with(['!', '?']) || body.ends_with('/') {
+            return None;
+        }
+        let name: String = body
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| !c.is_whitespace())
+            .collect();
+        if name.is_empty() {
+            return None;
+        }
+        Some(Tag {
+            start,
+            end,
+            name,
+            closing: body.starts_with('/'),
+        })
+    }
+}
+
+// The tags that start at or after `from`, nearest first.
+fn tags_after<'a>(text: &RopeSlice<'a>, from: usize) -> impl Iterator<Item = Tag> + 'a {
+    let mut chars = text.chars_at(from);
+    let mut j = from;
+    std::iter::from_fn(move || {
+        let mut start = None;
+        let mut body = String::new();
+        loop {
+            let c = chars.next()?;
+            j += 1;
+            // Anything can go inside a comment, which only ends at `-->`.
+            let comment = body.starts_with("!--");
+            match (c, start) {
+                ('<', _) if !comment => {
+                    start = Some(j - 1);
+                    body.clear();
+                }
+                ('>', Some(start_at)) if !comment || body.ends_with("--") => match Tag::parse(start_at, j - 1, &body) {
+                    Some(tag) => return Some(tag),
+                    None => {
+                        start = None;
+                        body.clear();
+                    }
+                },
+                (c, Some(_)) => body.push(c),
+                _ => (),
+            }
+        }
+    })
+}
+
+// The tags that end before `to`, nearest first.
+fn tags_before<'a>(text: &RopeSlice<'a>, to: usize) -> impl Iterator<Item = Tag> + 'a {
+    let mut chars = text.chars_at(to);
+    let mut j = to;
+    std::iter::from_fn(move || {
+        let mut end = None;
+        let mut body = Vec::new();
+        loop {
+            let c = chars.prev()?;
+            j -= 1;
+            // The body is read backwards, so a comment shows up as `--` first and `!--` last.
+            let comment = body.starts_with(&['-', '-']) && !body.ends_with(&['-', '-', '!']);
+            match (c, end) {
+                ('>', _) if !comment => {
+                    end = Some(j);
+                    body.clear();
+                }
+                ('<', Some(end_at)) if !comment => {
+                    let forward: String = body.iter().rev().collect();
+                    match Tag::parse(j, end_at, &forward) {
+                        Some(tag) => return Some(tag),
+                        None => {
+                            end = None;
+                            body.clear();
+                        }
+                    }
+                }
+                (c, Some(_)) => body.push(c),
+                _ => (),
+            }
+        }
+    })
+}
+
+// The tag that closes `open`, skipping over tags of the same name nested inside it.
+fn closing_tag(text: &RopeSlice, open: &Tag) -> Option<Tag> {
+    let mut depth = 0;
+    for tag in tags_after(text, open.end + 1).filter(|v| v.name == open.name) {
+        if !tag.closing {
+            depth += 1;
+        } else if depth > 0 {
+            depth -= 1;
+        } else {
+            return Some(tag);
+        }
+    }
+    None
+}
+
+// Works outward from the cursor, so only the text up to the `count`th enclosing pair is looked at.
+// A cursor on a tag counts as inside the pair the tag belongs to.
+fn tag(text: &RopeSlice, i: usize, count: usize, around: bool) -> Option<Selection> {
+    let line_start = text.line_to_char(text.char_to_line(i));
+    let on = (line_start..=i)
+        .rev()
+        .find(|&j| text.char(j) == '<' || text.char(j) == '>' && j < i)
+        .filter(|&j| text.char(j) == '<')
+        .and_then(|j| tags_after(text, j).next().filter(|v| v.start == j));
+    let to = match on {
+        Some(tag) if !tag.closing => tag.end + 1,
+        Some(tag) => tag.start,
+        None => i,
+    };
+
+    // Closing tags passed on the way out belong to pairs that end before the cursor.
+    let mut closed: Vec<String> = Vec::new();
+    let mut remaining = count;
+    for open in tags_before(text, to) {
+        if open.closing {
+            closed.push(open.name);
+            continue;
+        }
+        if let Some(k) = closed.iter().rposition(|v| *v == open.name) {
+            closed.truncate(k);
+            continue;
+        }
+
+        let close = match closing_tag(text, &open) {
+            Some(v) if v.end >= i => v,
+            _ => continue,
+        };
+        remaining -= 1;
+        if remaining > 0 {
+            continue;
+        }
+
+        return if around {
+            Some(chars(open.start, close.end))
+        } else if close.start > open.end + 1 {
+            Some(chars(open.end + 1, close.start - 1))
+        } else {
+            None
+        };
+    }
+    None
+}
+
+fn is_blank(text: &RopeSlice, line: usize) -> bool {
+    text.line(line).chars().all(char::is_whitespace)
+}
+
+// Paragraphs and the blank lines between them each count as one for `ip`. `ap` takes a paragraph
+// with the blank lines after it, or before it if there are none after.
+fn paragraph(text: &RopeSlice, i: usize, count: usize, around: bool) -> Selection {
+    let last = text.len_lines() - 1;
+    let line = text.char_to_line(i);
+    let blank = is_blank(text, line);
+
+    let run_end = |mut line: usize| {
+        let blank = is_blank(text, line);
+        while line < last && is_blank(text, line + 1) == blank {
+            line += 1;
+        }
+        line
+    };
+
+    let mut start = line;
+    while start > 0 && is_blank(text, start - 1) == blank {
+        start -= 1;
+    }
+    let mut end = run_end(line);
+    let runs = if around { count * 2 } else { count };
+    for _ in 1..runs {
+        if end == last {
+            break;
+        }
+        end = run_end(end + 1);
+    }
+
+    // A paragraph at the end of the text takes the blank lines before it instead.
+    if around && !blank && !is_blank(text, end) {
+        while start > 0 && is_blank(text, start - 1) {
+            start -= 1;
+        }
+    }
+
+    Selection {
+        kind: SelectionKind::Line,
+        anchor: text.line_to_char(start),
+        cursor: text.line_to_char(end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ropey::Rope;
+
+    use super::*;
+
+    // The text `keys` selects with the cursor on the first `|`, which is taken out of the text.
+    fn select(text: &str, keys: &str, count: usize) -> Option<String> {
+        let i = text.find('|').unwrap();
+        let text = Rope::from_str(&text.replacen('|', "", 1));
+        let mut chars = keys.chars();
+        let around = chars.next() == Some('a');
+        let object = TextObject::from_key(around, chars.next().unwrap()).unwrap();
+        let selection = object.select(&text.slice(..), i, count, &WordChars::parse(""))?;
+        let (start, end) = (selection.anchor, selection.cursor);
+        Some(match selection.kind {
+            SelectionKind::Line => {
+                let first = text.char_to_line(start);
+                let last = text.char_to_line(end);
+                text.slice(text.line_to_char(first)..text.line_to_char(last + 1)).to_string()
+            }
+            _ => text.slice(start..end + 1).to_string(),
+        })
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(select("one t|wo three", "iw", 1).as_deref(), Some("two"));
+        assert_eq!(select("one t|wo three", "aw", 1).as_deref(), Some("two "));
+        assert_eq!(select("one two t|hree", "aw", 1).as_deref(), Some(" three"));
+        assert_eq!(select("one t|wo three", "iw", 3).as_deref(), Some("two three"));
+        assert_eq!(select("one|  two", "iw", 1).as_deref(), Some("  "));
+        assert_eq!(select("one |  two", "aw", 1).as_deref(), Some("   two"));
+        assert_eq!(select("a.b|.c d", "iW", 1).as_deref(), Some("a.b.c"));
+        assert_eq!(select("f|oo.bar", "iw", 1).as_deref(), Some("foo"));
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(select(r#"say "h|i there" now"#, r#"i""#, 1).as_deref(), Some("hi there"));
+        assert_eq!(select(r#"say "h|i there" now"#, r#"a""#, 1).as_deref(), Some(r#""hi there" "#));
+        assert_eq!(select(r#"s|ay "hi" now"#, r#"i""#, 1).as_deref(), Some("hi"));
+        assert_eq!(select(r#"x = 'a\'|b'"#, "i'", 1).as_deref(), Some(r"a\'b"));
+        assert_eq!(select(r#"say "|" now"#, r#"i""#, 1), None);
+        assert_eq!(select("no qu|otes", r#"i""#, 1), None);
+    }
+
+    #[test]
+    fn brackets() {
+        assert_eq!(select("f(a, (b|, c))", "i(", 1).as_deref(), Some("b, c"));
+        assert_eq!(select("f(a, (b|, c))", "a)", 1).as_deref(), Some("(b, c)"));
+        assert_eq!(select("f(a, (b|, c))", "ib", 2).as_deref(), Some("a, (b, c)"));
+        assert_eq!(select("f(a, (b, c)|)", "i(", 1).as_deref(), Some("a, (b, c)"));
+        assert_eq!(select("[1, |2]", "i]", 1).as_deref(), Some("1, 2"));
+        assert_eq!(select("a (b|) c", "i{", 1), None);
+    }
+
+    #[test]
+    fn brackets_over_lines() {
+        let text = "fn f() {\n    let x = 1;|\n    x\n}\n";
+        assert_eq!(select(text, "i{", 1).as_deref(), Some("    let x = 1;\n    x\n"));
+        assert_eq!(select(text, "a{", 1).as_deref(), Some("{\n    let x = 1;\n    x\n}"));
+    }
+
+    #[test]
+    fn tags() {
+        let text = "<div><p>some <b>t|ext</b></p></div>";
+        assert_eq!(select(text, "it", 1).as_deref(), Some("text"));
+        assert_eq!(select(text, "at", 1).as_deref(), Some("<b>text</b>"));
+        assert_eq!(select(text, "it", 2).as_deref(), Some("some <b>text</b>"));
+        assert_eq!(select(text, "at", 3).as_deref(), Some(text.replace('|', "").as_str()));
+        assert_eq!(select("<br/><p>|x</p>", "it", 1).as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn tags_around_the_cursor() {
+        let text = "<a><b>x</b><b>y</b></a>";
+        assert_eq!(select("<a><|b>x</b><b>y</b></a>", "at", 1).as_deref(), Some("<b>x</b>"));
+        assert_eq!(select("<a><b>x</|b><b>y</b></a>", "at", 1).as_deref(), Some("<b>x</b>"));
+        assert_eq!(select("<a><b>x</b><b>y</b>|</a>", "at", 1).as_deref(), Some(text));
+        assert_eq!(select("<a><b>x</b>| <b>y</b></a>", "it", 1).as_deref(), Some("<b>x</b> <b>y</b>"));
+        assert_eq!(select("<a>x</a> |y", "it", 1), None);
+    }
+
+    #[test]
+    fn tags_that_do_not_pair() {
+        assert_eq!(select("<ul><li>one<li>t|wo</ul>", "it", 1).as_deref(), Some("<li>one<li>two"));
+        assert_eq!(select("<p>a<br>b|<!-- </p> --></p>", "it", 1).as_deref(), Some("a<br>b<!-- </p> -->"));
+        assert_eq!(select("<p><!-- <b> -->a|</p>", "it", 1).as_deref(), Some("<!-- <b> -->a"));
+        assert_eq!(select("<p>a > b| < c</p>", "it", 1).as_deref(), Some("a > b < c"));
+        assert_eq!(select("<div>\n  <p class=\"x\">\n    te|xt\n  </p>\n</div>", "at", 1).as_deref(), Some("<p class=\"x\">\n    text\n  </p>"));
+        assert_eq!(select("<div><div>a</div>|b</div>", "at", 1).as_deref(), Some("<div><div>a</div>b</div>"));
+    }
+
+    #[test]
+    fn paragraphs() {
+        let text = "one\ntw|o\n\n\nthree\n";
+        assert_eq!(select(text, "ip", 1).as_deref(), Some("one\ntwo\n"));
+        assert_eq!(select(text, "ap", 1).as_deref(), Some("one\ntwo\n\n\n"));
+        assert_eq!(select(text, "ip", 2).as_deref(), Some("one\ntwo\n\n\n"));
+        assert_eq!(select("one\n\nthr|ee", "ap", 1).as_deref(), Some("\nthree"));
+    }
+}
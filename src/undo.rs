@@ -0,0 +1,542 @@
+use std::{
+    env,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Clone)]
+pub enum Edit {
+    Insert { at: usize, text: String },
+    Delete { at: usize, text: String },
+}
+
+pub struct UndoNode {
+    pub seq: usize,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub edits: Vec<Edit>,
+    pub cursor_before: usize,
+    pub cursor_after: usize,
+    pub time: SystemTime,
+    redo_child: Option<usize>,
+}
+
+pub enum Step {
+    Undo(usize),
+    Redo(usize),
+}
+
+pub struct UndoTreeEntry {
+    pub seq: usize,
+    pub depth: usize,
+    pub time: SystemTime,
+    pub current: bool,
+    pub saved: bool,
+}
+
+// Every change ever committed is kept as a node, so undoing and then making a new change starts a
+// new branch instead of throwing the undone changes away. Node 0 is the original text, and the
+// index of every other node doubles as its sequence number.
+pub struct UndoTree {
+    nodes: Vec<UndoNode>,
+    current: usize,
+    saved: Option<usize>,
+    pending: Option<UndoNode>,
+}
+
+impl Default for UndoTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UndoTree {
+    pub fn new() -> Self {
+        UndoTree {
+            nodes: vec![UndoNode {
+                seq: 0,
+                parent: None,
+                children: Vec::new(),
+                edits: Vec::new(),
+                cursor_before: 0,
+                cursor_after: 0,
+                time: SystemTime::now(),
+                redo_child: None,
+            }],
+            current: 0,
+            saved: Some(0),
+            pending: None,
+        }
+    }
+
+    pub fn node(&self, seq: usize) -> &UndoNode {
+        &self.nodes[seq]
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn last(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.pending.is_some() || self.saved != Some(self.current)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.current);
+    }
+
+    pub fn record(&mut self, cursor_before: usize, edit: Edit) {
+        self.pending
+            .get_or_insert_with(|| UndoNode {
+                seq: 0,
+                parent: None,
+                children: Vec::new(),
+                edits: Vec::new(),
+                cursor_before,
+                cursor_after: cursor_before,
+                time: SystemTime::now(),
+                redo_child: None,
+            })
+            .edits
+            .push(edit);
+    }
+
+    pub fn commit(&mut self, cursor_after: usize) {
+        if let Some(mut node) = self.pending.take() {
+            let seq = self.nodes.len();
+            node.seq = seq;
+            node.parent = Some(self.current);
+            node.cursor_after = cursor_after;
+            node.time = SystemTime::now();
+            self.nodes[self.current].children.push(seq);
+            self.nodes[self.current].redo_child = Some(seq);
+            self.nodes.push(node);
+            self.current = seq;
+        }
+    }
+
+    pub fn undo_target(&self) -> Option<usize> {
+        self.nodes[self.current].parent
+    }
+
+    pub fn redo_target(&self) -> Option<usize> {
+        let node = &self.nodes[self.current];
+        node.redo_child.or_else(|| node.children.last().cloned())
+    }
+
+    // The state `count` changes before or after the current one in the order the changes were
+    // made, regardless of which branch they are on.
+    pub fn seq_target(&self, count: isize) -> usize {
+        (self.current as isize + count).clamp(0, self.last() as isize) as usize
+    }
+
+    pub fn time_target(&self, duration: Duration, forward: bool) -> usize {
+        let now = self.nodes[self.current].time;
+        let time = if forward {
+            match now.checked_add(duration) {
+                Some(v) => v,
+                None => return self.last(),
+            }
+        } else {
+            now.checked_sub(duration).unwrap_or(SystemTime::UNIX_EPOCH)
+        };
+        self.nodes
+            .iter()
+            .rev()
+            .find(|v| v.time <= time)
+            .map(|v| v.seq)
+            .unwrap_or(0)
+    }
+
+    // The steps needed to get from the current state to `target`: undo up to the closest common
+    // ancestor and then redo down to the target.
+    pub fn path(&self, target: usize) -> Vec<Step> {
+        let ancestors = |mut seq: usize| {
+            let mut path = vec![seq];
+            while let Some(parent) = self.nodes[seq].parent {
+                path.push(parent);
+                seq = parent;
+            }
+            path
+        };
+
+        let mut from = ancestors(self.current);
+        let mut to = ancestors(target);
+        while !from.is_empty() && from.last() == to.last() {
+            from.pop();
+            to.pop();
+        }
+
+        let mut steps: Vec<_> = from.into_iter().map(Step::Undo).collect();
+        steps.extend(to.into_iter().rev().map(Step::Redo));
+        steps
+    }
+
+    pub fn apply_step(&mut self, step: &Step) {
+        match *step {
+            Step::Undo(seq) => {
+                let parent = self.nodes[seq].parent.unwrap();
+                self.nodes[parent].redo_child = Some(seq);
+                self.current = parent;
+            }
+
+            Step::Redo(seq) => {
+                let parent = self.nodes[seq].parent.unwrap();
+                self.nodes[parent].redo_child = Some(seq);
+                self.current = seq;
+            }
+        }
+    }
+
+    // Lists every state depth first. The oldest child of a node continues its parent's branch and
+    // every later child starts a new, more indented branch.
+    pub fn entries(&self) -> Vec<UndoTreeEntry> {
+        let mut entries = Vec::new();
+        let mut stack = vec![(0, 0)];
+        while let Some((seq, depth)) = stack.pop() {
+            let node = &self.nodes[seq];
+            entries.push(UndoTreeEntry {
+                seq,
+                depth,
+                time: node.time,
+                current: seq == self.current,
+                saved: Some(seq) == self.saved,
+            });
+
+            for (i, &child) in node.children.iter().enumerate().rev() {
+                stack.push((child, if i == 0 { depth } else { depth + 1 }));
+            }
+        }
+        entries
+    }
+}
+
+impl UndoTree {
+    // Writes the tree to the undo cache for `path`. The state that matches the file on disk is
+    // stored as the current state along with the hash of the file's contents, so that the history
+    // can be thrown away if the file is changed by something other than nu.
+    pub fn save(&self, path: &Path, cache_dir: &Path, content_hash: u64) -> io::Result<()> {
+        let (path, file) = match cache_file(path, cache_dir) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        if self.nodes.len() == 1 {
+            return match fs::remove_file(file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+
+        let mut data = String::new();
+        let _ = writeln!(data, "nu-undo 2");
+        let _ = writeln!(data, "path {}", escape(&path.to_string_lossy()));
+        let _ = writeln!(data, "hash {:016x}", content_hash);
+        let _ = writeln!(data, "current {}", self.saved.unwrap_or(self.current));
+        for node in self.nodes.iter() {
+            let time = node.time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+            let _ = writeln!(
+                data,
+                "node {} {} {} {} {} {} {}",
+                node.parent.map(|v| v.to_string()).unwrap_or_else(|| String::from("-")),
+                time.as_secs(),
+                time.subsec_nanos(),
+                node.cursor_before,
+                node.cursor_after,
+                node.redo_child.map(|v| v.to_string()).unwrap_or_else(|| String::from("-")),
+                node.edits.len(),
+            );
+
+            for edit in node.edits.iter() {
+                let (kind, at, text) = match edit {
+                    Edit::Insert { at, text } => ('+', at, text),
+                    Edit::Delete { at, text } => ('-', at, text),
+                };
+                let _ = writeln!(data, "{} {} {}", kind, at, escape(text));
+            }
+        }
+
+        fs::create_dir_all(file.parent().unwrap())?;
+        fs::write(file, data)
+    }
+
+    // Reads the tree saved for `path`, if there is one. A history saved for different file
+    // contents is stale and is dropped without being loaded, but its cache file is kept: it may
+    // still be right for the file as another encoding reads it, and the next save replaces it.
+    pub fn load(path: &Path, cache_dir: &Path, content_hash: u64) -> Option<UndoTree> {
+        let (path, file) = cache_file(path, cache_dir)?;
+        let data = fs::read_to_string(&file).ok()?;
+        parse(&data, &path, content_hash)
+    }
+}
+
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &v| {
+        (hash ^ v as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Where undo histories are saved, one file per edited file.
+pub fn cache_dir() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CACHE_HOME") {
+        Some(v) if !v.is_empty() => PathBuf::from(v),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(dir.join("nu").join("undo"))
+}
+
+fn cache_file(path: &Path, cache_dir: &Path) -> Option<(PathBuf, PathBuf)> {
+    let path = fs::canonicalize(path).ok()?;
+    let name = format!("{:016x}", hash(path.to_string_lossy().as_bytes()));
+    Some((path, cache_dir.join(name)))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some(c) => result.push(c),
+                None => (),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn parse(data: &str, path: &Path, content_hash: u64) -> Option<UndoTree> {
+    let mut lines = data.lines();
+    if lines.next()? != "nu-undo 2"
+        || unescape(lines.next()?.strip_prefix("path ")?) != path.to_string_lossy()
+        || u64::from_str_radix(lines.next()?.strip_prefix("hash ")?, 16).ok()? != content_hash
+    {
+        return None;
+    }
+    let current: usize = lines.next()?.strip_prefix("current ")?.parse().ok()?;
+
+    let mut nodes: Vec<UndoNode> = Vec::new();
+    while let Some(line) = lines.next() {
+        let fields: Vec<_> = line.strip_prefix("node ")?.split(' ').collect();
+        if fields.len() != 7 {
+            return None;
+        }
+
+        let seq = nodes.len();
+        let parent: Option<usize> = match fields[0] {
+            "-" => None,
+            v => Some(v.parse().ok()?),
+        };
+        let redo_child = match fields[5] {
+            "-" => None,
+            v => Some(v.parse().ok()?),
+        };
+        let time = SystemTime::UNIX_EPOCH
+            + Duration::new(fields[1].parse().ok()?, fields[2].parse().ok()?);
+
+        let mut edits = Vec::new();
+        for _ in 0..fields[6].parse::<usize>().ok()? {
+            let mut parts = lines.next()?.splitn(3, ' ');
+            let kind = parts.next()?;
+            let at = parts.next()?.parse().ok()?;
+            let text = unescape(parts.next()?);
+            edits.push(match kind {
+                "+" => Edit::Insert { at, text },
+                "-" => Edit::Delete { at, text },
+                _ => return None,
+            });
+        }
+
+        match parent {
+            Some(parent) if parent < seq => nodes[parent].children.push(seq),
+            None if seq == 0 => (),
+            _ => return None,
+        }
+
+        nodes.push(UndoNode {
+            seq,
+            parent,
+            children: Vec::new(),
+            edits,
+            cursor_before: fields[3].parse().ok()?,
+            cursor_after: fields[4].parse().ok()?,
+            time,
+            redo_child,
+        });
+    }
+
+    if current >= nodes.len() || nodes.iter().any(|v| v.redo_child.map(|v| v >= nodes.len()).unwrap_or(false)) {
+        return None;
+    }
+
+    Some(UndoTree {
+        nodes,
+        current,
+        saved: Some(current),
+        pending: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(tree: &mut UndoTree, at: usize, text: &str) {
+        tree.record(at, Edit::Insert { at, text: text.to_owned() });
+        tree.commit(at + text.len());
+    }
+
+    fn walk(tree: &mut UndoTree, target: usize) {
+        for step in tree.path(target) {
+            tree.apply_step(&step);
+        }
+    }
+
+    // The path to `target` as (redo, seq) pairs.
+    fn steps(tree: &UndoTree, target: usize) -> Vec<(bool, usize)> {
+        tree.path(target)
+            .iter()
+            .map(|v| match *v {
+                Step::Undo(seq) => (false, seq),
+                Step::Redo(seq) => (true, seq),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edits_are_grouped_until_commit() {
+        let mut tree = UndoTree::new();
+        assert!(!tree.is_modified());
+        tree.record(0, Edit::Insert { at: 0, text: String::from("a") });
+        tree.record(1, Edit::Insert { at: 1, text: String::from("b") });
+        assert!(tree.is_modified());
+        assert_eq!(tree.last(), 0);
+
+        tree.commit(2);
+        assert_eq!(tree.last(), 1);
+        assert_eq!(tree.current(), 1);
+        let node = tree.node(1);
+        assert_eq!(node.edits.len(), 2);
+        assert_eq!((node.cursor_before, node.cursor_after), (0, 2));
+
+        // Committing with nothing recorded does not make an empty change.
+        tree.commit(2);
+        assert_eq!(tree.last(), 1);
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut tree = UndoTree::new();
+        insert(&mut tree, 0, "a");
+        insert(&mut tree, 1, "b");
+        assert_eq!(tree.undo_target(), Some(1));
+
+        tree.apply_step(&Step::Undo(2));
+        tree.apply_step(&Step::Undo(1));
+        assert_eq!(tree.current(), 0);
+        assert_eq!(tree.undo_target(), None);
+        assert_eq!(tree.redo_target(), Some(1));
+
+        tree.apply_step(&Step::Redo(1));
+        assert_eq!(tree.redo_target(), Some(2));
+    }
+
+    #[test]
+    fn new_changes_after_undo_start_a_branch() {
+        let mut tree = UndoTree::new();
+        insert(&mut tree, 0, "a");
+        insert(&mut tree, 1, "b");
+        walk(&mut tree, 1);
+        insert(&mut tree, 1, "c");
+        assert_eq!(tree.current(), 3);
+        assert_eq!(tree.node(1).children, vec![2, 3]);
+
+        // Redo follows the branch that was taken last.
+        tree.apply_step(&Step::Undo(3));
+        assert_eq!(tree.redo_target(), Some(3));
+
+        assert_eq!(steps(&tree, 2), vec![(true, 2)]);
+
+        walk(&mut tree, 3);
+        assert_eq!(steps(&tree, 2), vec![(false, 3), (true, 2)]);
+
+        let depths: Vec<_> = tree.entries().iter().map(|v| (v.seq, v.depth)).collect();
+        assert_eq!(depths, vec![(0, 0), (1, 0), (2, 0), (3, 1)]);
+    }
+
+    #[test]
+    fn seq_target_crosses_branches() {
+        let mut tree = UndoTree::new();
+        insert(&mut tree, 0, "a");
+        insert(&mut tree, 1, "b");
+        walk(&mut tree, 1);
+        insert(&mut tree, 1, "c");
+        walk(&mut tree, 2);
+        assert_eq!(tree.seq_target(1), 3);
+        assert_eq!(tree.seq_target(-5), 0);
+        assert_eq!(tree.seq_target(5), 3);
+    }
+
+    #[test]
+    fn time_target_past_the_end_of_time() {
+        let mut tree = UndoTree::new();
+        insert(&mut tree, 0, "a");
+        walk(&mut tree, 0);
+        assert_eq!(tree.time_target(Duration::MAX, true), 1);
+        assert_eq!(tree.time_target(Duration::MAX, false), 0);
+    }
+
+    #[test]
+    fn saved_state() {
+        let mut tree = UndoTree::new();
+        insert(&mut tree, 0, "a");
+        assert!(tree.is_modified());
+        tree.mark_saved();
+        assert!(!tree.is_modified());
+        tree.apply_step(&Step::Undo(1));
+        assert!(tree.is_modified());
+        tree.apply_step(&Step::Redo(1));
+        assert!(!tree.is_modified());
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        let text = "a\\nb\ncd\r\n";
+        assert_eq!(unescape(&escape(text)), text);
+        assert!(!escape(text).contains('\n'));
+    }
+
+    #[test]
+    fn mismatched_history_is_kept() {
+        let dir = env::temp_dir().join(format!("nu-undo-test-{}", std::process::id()));
+        let cache = dir.join("cache");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file.txt");
+        fs::write(&file, "text").unwrap();
+
+        let mut tree = UndoTree::new();
+        tree.record(0, Edit::Insert { at: 0, text: String::from("x") });
+        tree.commit(1);
+        tree.save(&file, &cache, 1).unwrap();
+
+        assert!(UndoTree::load(&file, &cache, 2).is_none());
+        let loaded = UndoTree::load(&file, &cache, 1).unwrap();
+        assert_eq!(loaded.last(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}